@@ -0,0 +1,43 @@
+use std::collections::{HashMap, HashSet};
+
+// Command line arguments in the form
+// distribute [command] [--option value] [--flag]
+pub struct Args {
+    pub command: String,
+    values: HashMap<String, String>,
+    flags: HashSet<String>,
+}
+
+impl Args {
+    pub fn parse(args: Vec<String>) -> Args {
+        let mut parsed = Args {
+            command: "run".to_string(),
+            values: HashMap::new(),
+            flags: HashSet::new(),
+        };
+        let mut i = 1;
+        if args.len() > 1 && !args[1].starts_with("--") {
+            parsed.command = args[1].clone();
+            i = 2;
+        }
+        while i < args.len() {
+            let name = args[i].trim_start_matches("--").to_string();
+            if i + 1 < args.len() && !args[i + 1].starts_with("--") {
+                parsed.values.insert(name, args[i + 1].clone());
+                i += 2;
+            } else {
+                parsed.flags.insert(name);
+                i += 1;
+            }
+        }
+        parsed
+    }
+
+    pub fn value(&self, name: &str) -> Option<&String> {
+        self.values.get(name)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+}
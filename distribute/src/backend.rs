@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+// Why a send didn't give a transfer
+#[derive(Debug)]
+pub enum SendError {
+    // no transfer was made so it's safe to send again
+    NotSent(String),
+    // the send command succeeded but the transfer wasn't in its output, so
+    // the transfer may have been made
+    Unknown(String),
+}
+
+// Everything distribute needs from the faucet and the safe client.
+// Errors mean the action was not done and it's safe to try again, except for
+// SendError::Unknown.
+pub trait Backend {
+    // Get tokens from the faucet, returns the transfer hex
+    fn fund_wallet(&mut self, amount: u32, wallet_pk_hex: &str) -> Result<String, String>;
+    fn create_wallet(&mut self, wallet_sk_hex: &str) -> Result<(), String>;
+    // Send tokens from the wallet, returns the transfer hex
+    fn send(&mut self, amount: &str, recipient_pk_hex: &str) -> Result<String, SendError>;
+    fn receive(&mut self, transfer_hex: &str) -> Result<(), String>;
+    // Upload a file to the network, returns the address of the file
+    fn upload(&mut self, path: &Path) -> Result<String, String>;
+}
+
+// Uses the faucet and safe binaries on $PATH.
+pub struct CliBackend;
+
+impl Backend for CliBackend {
+    fn fund_wallet(&mut self, amount: u32, wallet_pk_hex: &str) -> Result<String, String> {
+        // This uses the existing faucet functionality.
+        // This doesn't use the server function of the faucet
+        // because that will only issue 100 tokens at a time.
+        // It uses the faucet binary
+        // so must be run on the same machine as the faucet
+        // with the faucet binary on $PATH.
+        // the command is:
+        // faucet send amount to
+        let output = run("faucet", &["send", &amount.to_string(), wallet_pk_hex], "FAUCET")?;
+        // print any error
+        if !output.stderr.is_empty() {
+            println!("Faucet error:");
+            println!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+        // get the transfer from the output of the faucet
+        match parse_transfer(&String::from_utf8_lossy(&output.stdout)) {
+            Some(transfer_hex) => Ok(transfer_hex),
+            None => Err("Empty transfer from faucet".to_string()),
+        }
+    }
+
+    fn create_wallet(&mut self, wallet_sk_hex: &str) -> Result<(), String> {
+        run("safe", &["wallet", "create", wallet_sk_hex], "CREATE")?;
+        Ok(())
+    }
+
+    fn send(&mut self, amount: &str, recipient_pk_hex: &str) -> Result<String, SendError> {
+        let output = match run("safe", &["wallet", "send", amount, recipient_pk_hex], "SEND") {
+            Ok(o) => o,
+            Err(e) => return Err(SendError::NotSent(e)),
+        };
+        match parse_transfer(&String::from_utf8_lossy(&output.stdout)) {
+            Some(transfer_hex) => Ok(transfer_hex),
+            None => Err(SendError::Unknown(format!("Empty transfer to {}", recipient_pk_hex))),
+        }
+    }
+
+    fn receive(&mut self, transfer_hex: &str) -> Result<(), String> {
+        let output = run("safe", &["wallet", "receive", transfer_hex], "RECEIVE")?;
+        println!("RECEIVE STDOUT:\n{}", String::from_utf8_lossy(&output.stdout));
+        Ok(())
+    }
+
+    fn upload(&mut self, path: &Path) -> Result<String, String> {
+        let output = run("safe", &["files", "upload", path.as_os_str().to_str().unwrap()], "UPLOAD")?;
+        match parse_upload_address(&String::from_utf8_lossy(&output.stdout)) {
+            Some(address) => Ok(address),
+            None => Err(format!("No address for uploaded file {}", path.display())),
+        }
+    }
+}
+
+// Runs the command, printing the output if it fails.
+fn run(program: &str, args: &[&str], label: &str) -> Result<Output, String> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Unable to run {}: {}", program, e)),
+    };
+    if !output.status.success() {
+        println!("{} STDOUT:\n{}", label, String::from_utf8_lossy(&output.stdout));
+        println!("{} STDERR:\n{}", label, String::from_utf8_lossy(&output.stderr));
+        return Err(format!("{} {} failed, status {}", program, args[0], output.status));
+    }
+    Ok(output)
+}
+
+// Transfers are printed as a single long line of hex
+pub fn parse_transfer(stdout: &str) -> Option<String> {
+    let mut transfer_hex = None;
+    for line in stdout.split("\n") {
+        if line.len() > 100 && hex::decode(line).is_ok() {
+            transfer_hex = Some(line.to_string());
+        }
+    }
+    transfer_hex
+}
+
+// Uploaded file addresses are 32 bytes of hex within the output
+pub fn parse_upload_address(stdout: &str) -> Option<String> {
+    let mut address = None;
+    for word in stdout.split_whitespace() {
+        if word.len() == 64 && hex::decode(word).is_ok() {
+            address = Some(word.to_string());
+        }
+    }
+    address
+}
+
+// An in-memory faucet, wallet and network so the whole distribution can be
+// run without any network or binaries.
+#[derive(Default)]
+pub struct MockBackend {
+    pub wallet_sk_hex: Option<String>,
+    pub balance: u64,
+    // transfer hex -> (amount, recipient public key hex)
+    pub transfers: HashMap<String, (u64, String)>,
+    pub received: Vec<String>,
+    // address -> file content
+    pub uploads: HashMap<String, Vec<u8>>,
+}
+
+impl MockBackend {
+    fn new_transfer(&mut self, amount: u64, recipient_pk_hex: &str) -> String {
+        let transfer = format!("mock transfer {} of {} to {}", self.transfers.len(), amount, recipient_pk_hex);
+        let transfer_hex = hex::encode(transfer);
+        self.transfers.insert(transfer_hex.clone(), (amount, recipient_pk_hex.to_string()));
+        transfer_hex
+    }
+}
+
+impl Backend for MockBackend {
+    fn fund_wallet(&mut self, amount: u32, wallet_pk_hex: &str) -> Result<String, String> {
+        Ok(self.new_transfer(amount as u64, wallet_pk_hex))
+    }
+
+    fn create_wallet(&mut self, wallet_sk_hex: &str) -> Result<(), String> {
+        self.wallet_sk_hex = Some(wallet_sk_hex.to_string());
+        Ok(())
+    }
+
+    fn send(&mut self, amount: &str, recipient_pk_hex: &str) -> Result<String, SendError> {
+        if self.wallet_sk_hex.is_none() {
+            return Err(SendError::NotSent("No wallet".to_string()));
+        }
+        let amount = match amount.parse::<u64>() {
+            Ok(a) => a,
+            Err(_) => return Err(SendError::NotSent(format!("Invalid amount {}", amount))),
+        };
+        if amount > self.balance {
+            return Err(SendError::NotSent(format!("Insufficient balance {} to send {}", self.balance, amount)));
+        }
+        self.balance -= amount;
+        Ok(self.new_transfer(amount, recipient_pk_hex))
+    }
+
+    fn receive(&mut self, transfer_hex: &str) -> Result<(), String> {
+        if self.wallet_sk_hex.is_none() {
+            return Err("No wallet".to_string());
+        }
+        if self.received.iter().any(|t| t == transfer_hex) {
+            return Err("Transfer already received".to_string());
+        }
+        let amount = match self.transfers.get(transfer_hex) {
+            Some((amount, _)) => *amount,
+            None => return Err("Unknown transfer".to_string()),
+        };
+        self.balance += amount;
+        self.received.push(transfer_hex.to_string());
+        Ok(())
+    }
+
+    fn upload(&mut self, path: &Path) -> Result<String, String> {
+        let content = match fs::read(path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+        };
+        let address = sha256::digest(content.as_slice());
+        self.uploads.insert(address.clone(), content);
+        Ok(address)
+    }
+}
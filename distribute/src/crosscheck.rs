@@ -0,0 +1,82 @@
+use crate::snapshot::Snapshot;
+use crate::OMaidBalance;
+use std::collections::{BTreeSet, HashMap};
+
+// Balances can only be compared if every source is for the same block.
+pub fn check_block_heights(snapshots: &[Snapshot]) -> Result<(), String> {
+    let first = &snapshots[0].provenance;
+    for s in &snapshots[1..] {
+        if s.provenance.block_height != first.block_height {
+            return Err(format!(
+                "Balance sources are for different blocks, {} is at block {} but {} is at block {}",
+                first.source, first.block_height, s.provenance.source, s.provenance.block_height,
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Compares the balances from every source by address, balance and reserved.
+// Returns a line for each address where the sources differ by more than
+// tolerance, which is in whole MAID.
+pub fn find_discrepancies(source_balances: &[Vec<OMaidBalance>], tolerance: u64) -> Vec<String> {
+    let balances: Vec<HashMap<&String, &OMaidBalance>> = source_balances.iter().map(|s| {
+        s.iter().map(|b| (&b.address, b)).collect()
+    }).collect();
+    let addresses: BTreeSet<&String> = balances.iter().flat_map(|b| b.keys().copied()).collect();
+    let mut discrepancies = Vec::<String>::new();
+    for address in addresses {
+        let found: Vec<Option<&OMaidBalance>> = balances.iter().map(|b| b.get(address).copied()).collect();
+        let balance: Vec<Option<&String>> = found.iter().map(|f| f.map(|b| &b.balance)).collect();
+        let reserved: Vec<Option<&String>> = found.iter().map(|f| f.map(|b| &b.reserved)).collect();
+        if !within_tolerance(&balance, tolerance) {
+            discrepancies.push(format!("{} balance {}", address, show_values(&balance)));
+        }
+        if !within_tolerance(&reserved, tolerance) {
+            discrepancies.push(format!("{} reserved {}", address, show_values(&reserved)));
+        }
+    }
+    discrepancies
+}
+
+// A missing address counts as zero, so an address that one source leaves out
+// because it has no balance is not a discrepancy. Values that aren't whole
+// numbers must match exactly.
+fn within_tolerance(values: &[Option<&String>], tolerance: u64) -> bool {
+    let mut amounts = Vec::<u64>::new();
+    for v in values {
+        match v {
+            None => amounts.push(0),
+            Some(v) => match v.parse::<u64>() {
+                Ok(a) => amounts.push(a),
+                Err(_) => return values.iter().all(|other| other == &Some(*v)),
+            },
+        }
+    }
+    let max = amounts.iter().max().unwrap();
+    let min = amounts.iter().min().unwrap();
+    max - min <= tolerance
+}
+
+fn show_values(values: &[Option<&String>]) -> String {
+    let shown: Vec<String> = values.iter().map(|v| match v {
+        Some(v) => v.to_string(),
+        None => "missing".to_string(),
+    }).collect();
+    shown.join(" / ")
+}
+
+pub fn discrepancy_report(snapshots: &[Snapshot], discrepancies: &[String], tolerance: u64) -> String {
+    let mut report = format!("Balance sources disagree by more than {} MAID\n", tolerance);
+    for (i, s) in snapshots.iter().enumerate() {
+        report += &format!("Source {}: {}\n", i + 1, s.provenance.source);
+        report += &format!("    block {} sha256 {}\n", s.provenance.block_height, s.provenance.sha256);
+    }
+    report += &format!("Discrepancies: {}\n", discrepancies.len());
+    report += "Values are shown for each source in order\n";
+    for d in discrepancies {
+        report += d;
+        report += "\n";
+    }
+    report
+}
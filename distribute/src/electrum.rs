@@ -0,0 +1,247 @@
+use bitcoin::{Address, Network, Transaction};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const ELECTRUM_TIMEOUT_SECS: u64 = 60;
+const ELECTRUM_PROTOCOL_VERSION: &str = "1.4";
+
+// A client for the electrum protocol, json-rpc over tcp with one message
+// per line. Responses are cached so lookups can be run again without asking
+// the server for everything again.
+// There's no tls so this is meant for a local electrs or fulcrum server.
+pub struct ElectrumClient {
+    pub server: String,
+    cache_dir: PathBuf,
+    connection: Option<BufReader<TcpStream>>,
+    next_id: u64,
+}
+
+// Electrum indexes addresses by the reversed sha256 of the output script
+fn scripthash(address: &str) -> Result<String, String> {
+    let script = match Address::from_str(address).map(|a| a.require_network(Network::Bitcoin)) {
+        Ok(Ok(a)) => a.script_pubkey(),
+        _ => return Err(format!("Invalid address {}", address)),
+    };
+    let mut scripthash = hex::decode(sha256::digest(script.as_bytes())).unwrap();
+    scripthash.reverse();
+    Ok(hex::encode(scripthash))
+}
+
+impl ElectrumClient {
+    pub fn new(server: &str) -> ElectrumClient {
+        ElectrumClient {
+            server: server.to_string(),
+            cache_dir: PathBuf::from(crate::CACHE_DIR),
+            connection: None,
+            next_id: 0,
+        }
+    }
+
+    // Every transaction involving the address, as txids
+    pub fn address_history(&mut self, address: &str) -> Result<Vec<String>, String> {
+        let history = self.call("blockchain.scripthash.get_history", json!([scripthash(address)?]))?;
+        let txids = match history.as_array() {
+            Some(h) => h.iter().filter_map(|tx| tx["tx_hash"].as_str().map(|t| t.to_string())).collect(),
+            None => return Err(format!("Invalid history for {}: {}", address, history)),
+        };
+        Ok(txids)
+    }
+
+    pub fn transaction(&mut self, txid: &str) -> Result<Transaction, String> {
+        let tx_hex = self.call("blockchain.transaction.get", json!([txid]))?;
+        let tx_bytes = match tx_hex.as_str().map(hex::decode) {
+            Some(Ok(b)) => b,
+            _ => return Err(format!("Invalid transaction {}: {}", txid, tx_hex)),
+        };
+        match bitcoin::consensus::deserialize(&tx_bytes) {
+            Ok(tx) => Ok(tx),
+            Err(e) => Err(format!("Invalid transaction {}: {}", txid, e)),
+        }
+    }
+
+    fn cache_key(&self, method: &str, params: &Value) -> String {
+        format!("electrum {} {} {}", self.server, method, params)
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        // only results are cached, so an error from the server is asked for
+        // again next time rather than remembered
+        let cache_key = self.cache_key(method, &params);
+        let result = crate::fetch_from_cache_or(&self.cache_dir.clone(), &cache_key, || {
+            let response = self.request(method, params)?;
+            if !response["error"].is_null() {
+                return Err(format!("Error from {}: {}", method, response["error"]));
+            }
+            Ok(response["result"].to_string())
+        })?;
+        match serde_json::from_str(&result) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(format!("Invalid cached result for {}: {}", method, e)),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        if self.connection.is_none() {
+            self.connect()?;
+        }
+        self.next_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": method,
+            "params": params,
+        });
+        let result = self.exchange(&request);
+        // a connection that failed part way through can't be used again
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn exchange(&mut self, request: &Value) -> Result<Value, String> {
+        let server = &self.server;
+        let connection = self.connection.as_mut().unwrap();
+        let mut line = request.to_string();
+        line.push('\n');
+        if let Err(e) = connection.get_mut().write_all(line.as_bytes()) {
+            return Err(format!("Error sending to electrum server {}: {}", server, e));
+        }
+        // skip any notifications until the response to this request
+        loop {
+            let mut response = String::new();
+            match connection.read_line(&mut response) {
+                Ok(0) => return Err(format!("Electrum server {} closed the connection", server)),
+                Ok(_) => {}
+                Err(e) => return Err(format!("Error reading from electrum server {}: {}", server, e)),
+            }
+            let parsed: Value = match serde_json::from_str(&response) {
+                Ok(p) => p,
+                Err(e) => return Err(format!("Invalid response from electrum server {}: {}", server, e)),
+            };
+            if parsed["id"] == request["id"] {
+                return Ok(parsed);
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), String> {
+        let stream = match TcpStream::connect(&self.server) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Unable to connect to electrum server {}: {}", self.server, e)),
+        };
+        let timeout = Some(Duration::from_secs(ELECTRUM_TIMEOUT_SECS));
+        if stream.set_read_timeout(timeout).and(stream.set_write_timeout(timeout)).is_err() {
+            return Err(format!("Unable to set timeouts for electrum server {}", self.server));
+        }
+        self.connection = Some(BufReader::new(stream));
+        // servers expect the version to be negotiated first
+        let response = self.request("server.version", json!(["maid_distribution", ELECTRUM_PROTOCOL_VERSION]))?;
+        if !response["error"].is_null() {
+            self.connection = None;
+            return Err(format!("Electrum server {} doesn't support protocol {}: {}", self.server, ELECTRUM_PROTOCOL_VERSION, response["error"]));
+        }
+        Ok(())
+    }
+}
+
+// The first request to the mock server for this transaction gets an error
+const MOCK_FLAKY_TXID: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+
+// Answers electrum requests on a local port the way electrs does, for tests.
+// Returns the server address and a thread that ends when the client
+// disconnects, with the methods that were called.
+fn mock_server(history: Value, transaction_hex: String) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let thread = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut methods = Vec::<String>::new();
+        let mut flaky_failed = false;
+        for line in BufReader::new(stream).lines() {
+            let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+            let method = request["method"].as_str().unwrap().to_string();
+            let mut reply = match method.as_str() {
+                "server.version" => json!({ "result": ["mock electrum", ELECTRUM_PROTOCOL_VERSION] }),
+                "blockchain.scripthash.get_history" => json!({ "result": history }),
+                "blockchain.transaction.get" if request["params"][0] == json!(MOCK_FLAKY_TXID) && !flaky_failed => {
+                    flaky_failed = true;
+                    json!({ "error": { "code": 2, "message": "daemon error" } })
+                }
+                "blockchain.transaction.get" => json!({ "result": transaction_hex }),
+                _ => json!({ "error": { "code": -32601, "message": "unknown method" } }),
+            };
+            methods.push(method);
+            // a notification first, which the client must skip
+            let notification = json!({ "jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "params": [] });
+            reply["jsonrpc"] = json!("2.0");
+            reply["id"] = request["id"].clone();
+            writer.write_all(format!("{}\n{}\n", notification, reply).as_bytes()).unwrap();
+        }
+        methods
+    });
+    (server, thread)
+}
+
+pub fn tests() {
+    let address = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    let tx = Transaction {
+        version: bitcoin::transaction::Version::ONE,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input: vec![bitcoin::TxIn::default()],
+        output: vec![bitcoin::TxOut {
+            value: bitcoin::Amount::from_sat(1000),
+            script_pubkey: Address::from_str(address).unwrap().assume_checked().script_pubkey(),
+        }],
+    };
+    let txid = tx.txid().to_string();
+    // responses are cached in a temporary directory, not the operator's cache
+    let cache_dir = std::env::temp_dir().join(format!("distribute_electrum_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_dir);
+    // history and transactions are looked up over one connection
+    {
+        let history = json!([{ "tx_hash": txid, "height": 800000 }]);
+        let (server, thread) = mock_server(history, bitcoin::consensus::encode::serialize_hex(&tx));
+        let mut client = ElectrumClient::new(&server);
+        client.cache_dir = cache_dir.clone();
+        let txids = client.address_history(address);
+        assert!(txids == Ok(vec![txid.clone()]), "Electrum history is {:?}", txids);
+        let found = client.transaction(&txid);
+        assert!(found == Ok(tx.clone()), "Electrum transaction is {:?}", found);
+        assert!(client.address_history("not an address").is_err(), "Invalid address did not return error");
+        // error replies aren't cached, so asking again gets the result
+        let flaky = client.transaction(MOCK_FLAKY_TXID);
+        assert!(flaky.is_err(), "Electrum error did not return error");
+        let flaky = client.transaction(MOCK_FLAKY_TXID);
+        assert!(flaky == Ok(tx.clone()), "Electrum error was cached: {:?}", flaky);
+        // results are cached
+        assert!(client.transaction(&txid) == Ok(tx.clone()), "Cached transaction is wrong");
+        let cached_keys = [
+            client.cache_key("blockchain.scripthash.get_history", &json!([scripthash(address).unwrap()])),
+            client.cache_key("blockchain.transaction.get", &json!([txid])),
+            client.cache_key("blockchain.transaction.get", &json!([MOCK_FLAKY_TXID])),
+        ];
+        drop(client);
+        let methods = thread.join().unwrap();
+        let expected = ["server.version", "blockchain.scripthash.get_history", "blockchain.transaction.get", "blockchain.transaction.get", "blockchain.transaction.get"];
+        assert!(methods == expected, "Electrum server was asked for {:?}", methods);
+        for key in cached_keys {
+            assert!(crate::cache_filename(&cache_dir, &key).exists(), "Result for {} was not cached", key);
+        }
+    }
+    // a server that isn't running is an error
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut client = ElectrumClient::new(&server);
+        client.cache_dir = cache_dir.clone();
+        assert!(client.transaction(&txid).is_err(), "Missing electrum server did not return error");
+    }
+    let _ = std::fs::remove_dir_all(&cache_dir);
+}
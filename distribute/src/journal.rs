@@ -0,0 +1,291 @@
+use crate::snapshot::SnapshotProvenance;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// The journal is an append-only file of json lines, one line per state change.
+// Every line is fsync'd before the action it describes is taken, so a run
+// that dies at any point can be resumed by replaying the file.
+//
+// Each address moves through these states in order:
+// planned -> key_generated -> sending -> sent -> encrypted -> published
+//
+// sending is written immediately before the transfer is made. If a run stops
+// while an address is in the sending state it's not possible to know whether
+// the transfer was made, so the rerun stops and asks the operator to check.
+//
+// Funding the wallet is faucet_transfer, written as soon as the faucet returns
+// the transfer, then funded once the wallet has received it. A rerun with a
+// faucet_transfer and no funded receives that transfer instead of using the
+// faucet again.
+//
+// key_generated has the recipient secret key, which is only needed until the
+// distribution is encrypted. remove_encrypted_keys rewrites the journal
+// without the keys of encrypted distributions.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JournalEntry {
+    // The snapshot of balances this distribution is for.
+    Snapshot {
+        provenance: SnapshotProvenance,
+    },
+    // The faucet made a transfer to the distribution wallet.
+    FaucetTransfer {
+        amount: u32,
+        transfer: String,
+    },
+    // Tokens were received into the distribution wallet from the faucet.
+    Funded {
+        amount: u32,
+    },
+    Planned {
+        address: String,
+        amount: String,
+        public_key: String,
+    },
+    KeyGenerated {
+        address: String,
+        secret_key: String,
+    },
+    Sending {
+        address: String,
+    },
+    // The send command reported failure so no transfer was made and it's
+    // safe to send again.
+    SendFailed {
+        address: String,
+    },
+    Sent {
+        address: String,
+        transfer: String,
+    },
+    Encrypted {
+        address: String,
+        encrypted_md: String,
+    },
+    // The list of all encrypted distributions was uploaded.
+    // Every address that was encrypted at this point is included in the list.
+    Published {
+        csv_address: String,
+    },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AddressProgress {
+    pub amount: String,
+    pub public_key: String,
+    pub secret_key: Option<String>,
+    pub sending: bool,
+    pub transfer: Option<String>,
+    pub encrypted_md: Option<String>,
+    pub published: bool,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    progress: HashMap<String, AddressProgress>,
+    snapshot: Option<SnapshotProvenance>,
+    funded: u32,
+    // amount and transfer hex of a faucet transfer not yet received
+    faucet_transfer: Option<(u32, String)>,
+    csv_address: Option<String>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Journal {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        let mut body = String::new();
+        file.read_to_string(&mut body).unwrap();
+        // A crash while appending can leave a partial last line.
+        // Nothing was acted on for that line so it's safe to drop it.
+        let complete_len = match body.rfind('\n') {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        if complete_len < body.len() {
+            println!("Removing incomplete last line from journal {}", path.display());
+            file.set_len(complete_len as u64).unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.sync_all().unwrap();
+        }
+        let mut journal = Journal {
+            path: path.to_path_buf(),
+            file,
+            progress: HashMap::new(),
+            snapshot: None,
+            funded: 0,
+            faucet_transfer: None,
+            csv_address: None,
+        };
+        for (i, line) in body[..complete_len].lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: JournalEntry = match serde_json::from_str(line) {
+                Ok(e) => e,
+                Err(e) => panic!("Invalid journal entry on line {} of {}: {}", i + 1, path.display(), e),
+            };
+            journal.apply(entry);
+        }
+        journal
+    }
+
+    // Writes the entry to disk before updating the in-memory state.
+    pub fn record(&mut self, entry: JournalEntry) {
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).unwrap();
+        self.file.sync_all().unwrap();
+        self.apply(entry);
+    }
+
+    pub fn progress(&self, address: &str) -> Option<&AddressProgress> {
+        self.progress.get(address)
+    }
+
+    pub fn snapshot(&self) -> Option<&SnapshotProvenance> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn funded(&self) -> u32 {
+        self.funded
+    }
+
+    pub fn faucet_transfer(&self) -> Option<&(u32, String)> {
+        self.faucet_transfer.as_ref()
+    }
+
+    pub fn csv_address(&self) -> Option<&String> {
+        self.csv_address.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Rewrites the journal without the secret keys of distributions that have
+    // been encrypted, so the only keys left are the ones needed to resume.
+    // The new journal replaces the old one in a single rename so a crash
+    // leaves one or the other. Returns the number of keys removed.
+    pub fn remove_encrypted_keys(&mut self) -> usize {
+        let body = fs::read_to_string(&self.path).unwrap();
+        let mut kept = String::new();
+        let mut removed = 0;
+        for line in body.lines() {
+            // every line was read when the journal was opened or written since
+            if let Ok(JournalEntry::KeyGenerated { address, .. }) = serde_json::from_str(line) {
+                if self.progress.get(&address).is_some_and(|p| p.encrypted_md.is_some()) {
+                    removed += 1;
+                    continue;
+                }
+            }
+            kept += line;
+            kept.push('\n');
+        }
+        if removed == 0 {
+            return 0;
+        }
+        distribution_core::write_atomic(&self.path, kept.as_bytes()).unwrap();
+        self.file = fs::OpenOptions::new().append(true).open(&self.path).unwrap();
+        for p in self.progress.values_mut() {
+            if p.encrypted_md.is_some() {
+                p.secret_key = None;
+            }
+        }
+        removed
+    }
+
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::Snapshot { provenance } => {
+                self.snapshot = Some(provenance);
+            }
+            JournalEntry::FaucetTransfer { amount, transfer } => {
+                self.faucet_transfer = Some((amount, transfer));
+            }
+            JournalEntry::Funded { amount } => {
+                self.funded += amount;
+                self.faucet_transfer = None;
+            }
+            JournalEntry::Planned { address, amount, public_key } => {
+                let p = self.progress.entry(address).or_default();
+                p.amount = amount;
+                p.public_key = public_key;
+            }
+            JournalEntry::KeyGenerated { address, secret_key } => {
+                self.progress.entry(address).or_default().secret_key = Some(secret_key);
+            }
+            JournalEntry::Sending { address } => {
+                self.progress.entry(address).or_default().sending = true;
+            }
+            JournalEntry::SendFailed { address } => {
+                self.progress.entry(address).or_default().sending = false;
+            }
+            JournalEntry::Sent { address, transfer } => {
+                let p = self.progress.entry(address).or_default();
+                p.sending = false;
+                p.transfer = Some(transfer);
+            }
+            JournalEntry::Encrypted { address, encrypted_md } => {
+                self.progress.entry(address).or_default().encrypted_md = Some(encrypted_md);
+            }
+            JournalEntry::Published { csv_address } => {
+                for p in self.progress.values_mut() {
+                    if p.encrypted_md.is_some() {
+                        p.published = true;
+                    }
+                }
+                self.csv_address = Some(csv_address);
+            }
+        }
+    }
+}
+
+pub fn tests() {
+    let dir = std::env::temp_dir().join(format!("distribute_journal_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("distribution_journal.jsonl");
+    let key_generated = |address: &str| JournalEntry::KeyGenerated {
+        address: address.to_string(),
+        secret_key: format!("secret key for {}", address),
+    };
+    let encrypted = |address: &str| JournalEntry::Encrypted {
+        address: address.to_string(),
+        encrypted_md: format!("distribution for {}", address),
+    };
+    // only the keys of encrypted distributions are removed
+    {
+        let mut journal = Journal::open(&path);
+        journal.record(key_generated("first"));
+        journal.record(encrypted("first"));
+        journal.record(key_generated("second"));
+        assert!(journal.remove_encrypted_keys() == 1, "Wrong number of keys removed");
+        let body = fs::read_to_string(&path).unwrap();
+        assert!(!body.contains("secret key for first"), "Key of encrypted distribution is in the journal:\n{}", body);
+        assert!(body.contains("secret key for second"), "Key needed to resume was removed:\n{}", body);
+        assert!(journal.progress("first").unwrap().secret_key.is_none(), "Removed key is still in memory");
+        // the journal is still appended to after being rewritten
+        journal.record(encrypted("second"));
+        assert!(journal.remove_encrypted_keys() == 1, "Key of later encrypted distribution was not removed");
+        assert!(journal.remove_encrypted_keys() == 0, "Keys were removed twice");
+    }
+    // the rewritten journal resumes the same progress
+    {
+        let journal = Journal::open(&path);
+        for address in ["first", "second"] {
+            let progress = journal.progress(address).unwrap();
+            assert!(progress.encrypted_md == Some(format!("distribution for {}", address)), "Progress for {} is {:?}", address, progress);
+            assert!(progress.secret_key.is_none(), "Key for {} is in the journal", address);
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
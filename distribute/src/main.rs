@@ -1,14 +1,35 @@
 use blsttc::SecretKey;
-use serde::{Deserialize, Serialize};
+use distribution_core::{MaidDistribution, OMaidBalance};
 use std::env;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
-use std::process::Command;
 use std::time::SystemTime;
 
-const OMNI_BALANCES_URL: &str = "https://api.omniexplorer.info/ask.aspx?api=getpropertybalances&prop=3";
+mod args;
+mod backend;
+mod crosscheck;
+mod electrum;
+mod journal;
+mod plan;
+mod pubkeys;
+mod pull;
+mod scan;
+mod snapshot;
+mod sources;
+
+use args::Args;
+use backend::{Backend, CliBackend, MockBackend, SendError};
+use crosscheck::{check_block_heights, discrepancy_report, find_discrepancies};
+use journal::{Journal, JournalEntry};
+use plan::{check_approved_plan, create_plan, plan_snapshot_path, print_plan_summary, save_plan, DistributionPlan};
+use pubkeys::{validate_public_key, RejectedKey};
+use pull::pull_keys;
+use scan::scan_public_keys;
+use snapshot::{Snapshot, SNAPSHOT_FILE};
+use sources::{balance_sources, BalanceSource};
+
 const CACHE_DIR: &str = "cache";
 const CACHE_EXPIRY_SECS: u64 = 3600;
 
@@ -16,40 +37,116 @@ const CACHE_EXPIRY_SECS: u64 = 3600;
 // replace this hardcoded secret with distributed keygen using bls_dkg crate
 const WALLET_SECRET_KEY: &str = "5920222d8798f74d09b3cdb6847e145197d52f471e42c83fe728f3dce6ce2878";
 const ENCRYPTED_MD_DIR: &str = "encrypted_maid_distributions";
+// Contains recipient secret keys until they're encrypted, keep it private.
+// The keys are removed from it once their distributions are encrypted.
+const JOURNAL_FILE: &str = "distribution_journal.jsonl";
+const PLAN_FILE: &str = "distribution_plan.json";
+const DISCREPANCY_FILE: &str = "balance_discrepancies.txt";
+const REJECTED_KEYS_FILE: &str = "rejected_keys.txt";
+// Included in every distribution so it can't be claimed on the wrong network
+const DEFAULT_NETWORK_ID: &str = "mainnet";
+// Mock runs keep their journal and distributions separate from real runs
+const MOCK_RUN_DIR: &str = "mock_run";
 
 // Generated with bip39 phrase
 // wedding pig fiscal
 // bip44 derivation path m/44'/0'/0'/0/0
 const TEST_BITCOIN_ADDRESS: &str = "1LyVLuxCbgLgYCZ6Sk6BrPJqAhixuyJpP7";
 const TEST_BITCOIN_PUBLIC_KEY: &str = "02888b3476298033f5f6ac52f868d603ace34de8918944a2ecde9b61e751132926";
-const _TEST_BITCOIN_SECRET_KEY: &str = "KyNdvxT1Ead7AD9thdvg8399fVxC1Tdf9FvPc2dqmmnHstcTUH5y";
-
-#[derive(Serialize, Deserialize)]
-struct OMaidBalance {
-    address: String,
-    balance: String,
-    reserved: String,
-    public_key: Option<String>,
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct MaidDistribution {
-    #[serde(with = "serde_bytes")]
-    transfer: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    secret_key: Vec<u8>,
-}
+const TEST_BITCOIN_SECRET_KEY: &str = "KyNdvxT1Ead7AD9thdvg8399fVxC1Tdf9FvPc2dqmmnHstcTUH5y";
 
 fn main() {
+    let args = Args::parse(env::args().collect());
+    let commands = ["run", "plan", "snapshot", "scan-keys", "pull-keys"];
+    if !commands.contains(&args.command.as_str()) {
+        println!("Usage:");
+        println!("distribute plan [--output <plan file>] [balance source]");
+        println!("distribute run [--approved-plan <plan file>] [balance source] [--mock]");
+        println!("    --mock needs --snapshot <file>, or the snapshot of the approved plan, as the only balance source");
+        println!("    plan and run take [--network-id <safe network>], default {}", DEFAULT_NETWORK_ID);
+        println!("distribute snapshot [--output <snapshot file>] [balance source]");
+        println!("distribute scan-keys (--blocks-dir <dir> | --bitcoin-rpc <url> | --electrum <host:port>) [balance source]");
+        println!("    [--bitcoin-rpc-user <user>] [--bitcoin-rpc-cookie <cookie file>]");
+        println!("    [--from-height <height>] [--to-height <height>]");
+        println!("    password is read from BITCOIN_RPC_PASSWORD");
+        println!("distribute pull-keys --server <public_key_server url> [--round <round id>]");
+        println!("    admin token is read from ADMIN_TOKEN");
+        println!();
+        println!("Balance sources, default is omniexplorer:");
+        println!("--omniexplorer [--omniexplorer-url <url>]");
+        println!("--snapshot <json or csv file>");
+        println!("--omni-rpc <url> [--omni-rpc-user <user>] [--omni-rpc-cookie <cookie file>]");
+        println!("    password is read from OMNI_RPC_PASSWORD");
+        println!("--block-height <height> requires the snapshot to be for this block");
+        println!("When more than one source is given the balances from each are compared");
+        println!("--tolerance <maid> allowed difference per address, default 0");
+        process::exit(1);
+    }
 
     run_checks();
 
+    // Keys submitted to public_key_server, doesn't need the balances
+    if args.command == "pull-keys" {
+        pull_keys(&args);
+        return;
+    }
+
     println!("Fetching omni balances");
-    let omaid_balances = fetch_omni_balances();
+    let sources = match balance_sources(&args) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+    // a mock run is offline so the balances must come from a file
+    let snapshot_given = args.value("snapshot").is_some() || args.value("approved-plan").is_some();
+    if args.flag("mock") && (!snapshot_given || sources.len() > 1) {
+        println!("--mock needs --snapshot <file> as the only balance source so it runs offline");
+        process::exit(1);
+    }
+    let tolerance = match args.value("tolerance").map(|t| t.parse::<u64>()) {
+        None => 0,
+        Some(Ok(t)) => t,
+        Some(Err(_)) => {
+            println!("--tolerance must be a whole number of MAID");
+            process::exit(1);
+        }
+    };
+    let (snapshot, omaid_balances) = fetch_omni_balances(&sources, tolerance);
+    snapshot.print();
+    if let Some(block_height) = args.value("block-height") {
+        if block_height.parse::<u64>() != Ok(snapshot.provenance.block_height) {
+            println!("Snapshot is for block {} but block {} was requested", snapshot.provenance.block_height, block_height);
+            process::exit(1);
+        }
+    }
     println!("Total OMaid Balances: {}", omaid_balances.len());
 
-    let mut pubkey_balances = add_public_keys(&omaid_balances);
+    // Save the snapshot so it can be reviewed and used by later runs
+    if args.command == "snapshot" {
+        let default_snapshot_file = SNAPSHOT_FILE.to_string();
+        let snapshot_file = args.value("output").unwrap_or(&default_snapshot_file);
+        snapshot.save(Path::new(snapshot_file));
+        println!("Snapshot saved to {}", snapshot_file);
+        println!("Use it with --snapshot {}", snapshot_file);
+        return;
+    }
+
+    // Find public keys that are already on chain and add them to keys
+    if args.command == "scan-keys" {
+        if let Err(e) = scan_public_keys(&args, &omaid_balances) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let (mut pubkey_balances, rejected_keys) = add_public_keys(&omaid_balances);
     println!("Total balances with pubkeys: {}", pubkey_balances.len());
+    if !rejected_keys.is_empty() {
+        report_rejected_keys(&rejected_keys);
+    }
 
     // Add an extra entry for the test address.
     // In production this may be excluded.
@@ -60,20 +157,84 @@ fn main() {
         public_key: Some(TEST_BITCOIN_PUBLIC_KEY.to_string()),
     });
 
-    let distribution_amount = total_balance(&pubkey_balances);
-    // Need a little extra in the wallet to upload the data to the safe network
-    let upload_amount = 1;
-    let total_distributions_maid = distribution_amount + upload_amount;
-    println!("Total to be distributed: {}", total_distributions_maid);
+    let default_network_id = DEFAULT_NETWORK_ID.to_string();
+    let network_id = args.value("network-id").unwrap_or(&default_network_id);
+    let distribution_plan = create_plan(&snapshot.provenance, network_id, &omaid_balances, &pubkey_balances, &rejected_keys);
+    print_plan_summary(&distribution_plan);
+
+    // Plan mode never touches the faucet or the safe client
+    if args.command == "plan" {
+        let default_plan_file = PLAN_FILE.to_string();
+        let plan_file = args.value("output").unwrap_or(&default_plan_file);
+        let plan_hash = save_plan(&distribution_plan, Path::new(plan_file));
+        println!("Distribution plan saved to {}", plan_file);
+        println!("Plan sha256: {}", plan_hash);
+        let snapshot_filepath = plan_snapshot_path(Path::new(plan_file));
+        snapshot.save(&snapshot_filepath);
+        println!("Plan snapshot saved to {}", snapshot_filepath.display());
+        println!("Once approved, run with --approved-plan {}", plan_file);
+        return;
+    }
+    match args.value("approved-plan") {
+        Some(approved_plan) => {
+            if let Err(e) = check_approved_plan(&distribution_plan, Path::new(approved_plan)) {
+                println!("{}", e);
+                process::exit(1);
+            }
+        }
+        None => println!("No approved plan given, distributing without review"),
+    }
+
+    // The mock backend runs the whole distribution in memory without the
+    // faucet, the safe client or the network.
+    if args.flag("mock") {
+        println!("Using mock faucet and safe client, output is in {}", MOCK_RUN_DIR);
+        // the mock network only exists for this run so start again
+        let _ = fs::remove_dir_all(MOCK_RUN_DIR);
+        let mut mock_backend = MockBackend::default();
+        run_distribution(&mut mock_backend, Path::new(MOCK_RUN_DIR), &snapshot, &distribution_plan);
+        if let Err(e) = check_mock_distribution(&mock_backend, Path::new(MOCK_RUN_DIR)) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        println!("Mock distribution checked");
+        println!("Mock wallet balance remaining: {}", mock_backend.balance);
+        println!("Mock files uploaded: {}", mock_backend.uploads.len());
+        return;
+    }
+    run_distribution(&mut CliBackend, Path::new("."), &snapshot, &distribution_plan);
+}
+
+fn run_distribution(backend: &mut dyn Backend, run_dir: &Path, snapshot: &Snapshot, plan: &DistributionPlan) {
+    let _ = fs::create_dir_all(run_dir.join(ENCRYPTED_MD_DIR));
+    let mut journal = Journal::open(&run_dir.join(JOURNAL_FILE));
+    println!("Using journal {}", journal.path().display());
+    // a previous run may have stopped before removing its keys
+    remove_encrypted_keys(&mut journal);
+    check_journal_snapshot(&mut journal, snapshot, run_dir);
 
     println!("Fetching distribution balance from faucet");
-    load_tokens_into_distribution_wallet(total_distributions_maid);
+    load_tokens_into_distribution_wallet(backend, &mut journal, plan.total_from_faucet);
 
     println!("Creating distributions");
-    distribute_tokens(&pubkey_balances);
+    distribute_tokens(backend, run_dir, &mut journal, plan);
+    remove_encrypted_keys(&mut journal);
+}
+
+fn remove_encrypted_keys(journal: &mut Journal) {
+    let removed = journal.remove_encrypted_keys();
+    if removed > 0 {
+        println!("Removed {} secret keys of encrypted distributions from the journal", removed);
+    }
 }
 
 fn run_checks() {
+    distribution_core::tests();
+    electrum::tests();
+    sources::tests();
+    journal::tests();
+    scan::tests();
+    check_mock_run();
     // TODO
     // check if a wallet already exists and if so move it elsewhere
     // Check peers are available / can connect to network
@@ -82,26 +243,90 @@ fn run_checks() {
     // create encrypted md directory if not exist
 }
 
-fn fetch_omni_balances() -> Vec<OMaidBalance> {
-    // TODO print block height and current time
-    // TODO Consider whether to deal with transactions in mempool
-    let obody = fetch_from_cache_or_internet(OMNI_BALANCES_URL);
+// Balances come from the first source. If there are other sources they must
+// all agree with the first, so one bad response can't change the distribution.
+fn fetch_omni_balances(sources: &[Box<dyn BalanceSource>], tolerance: u64) -> (Snapshot, Vec<OMaidBalance>) {
+    let mut snapshots = Vec::<Snapshot>::new();
+    for source in sources {
+        match source.fetch() {
+            Ok(s) => snapshots.push(s),
+            Err(e) => {
+                println!("Unable to fetch balances: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    // every source checks its balances can be read when it's fetched
+    let mut source_balances = Vec::<Vec<OMaidBalance>>::new();
+    for snapshot in &snapshots {
+        match snapshot.balances() {
+            Ok(b) => source_balances.push(b),
+            Err(e) => {
+                println!("Unable to read balances from {}: {}", snapshot.provenance.source, e);
+                process::exit(1);
+            }
+        }
+    }
+    if snapshots.len() > 1 {
+        if let Err(e) = check_block_heights(&snapshots) {
+            println!("{}", e);
+            println!("Use sources that are at the same block, eg with --block-height for a file");
+            process::exit(1);
+        }
+        let discrepancies = find_discrepancies(&source_balances, tolerance);
+        if !discrepancies.is_empty() {
+            let report = discrepancy_report(&snapshots, &discrepancies, tolerance);
+            print!("{}", report);
+            let mut file = fs::File::create(DISCREPANCY_FILE).unwrap();
+            file.write_all(report.as_bytes()).unwrap();
+            println!("Discrepancy report saved to {}", DISCREPANCY_FILE);
+            process::exit(1);
+        }
+        println!("All {} balance sources agree", snapshots.len());
+    }
+    let snapshot = snapshots.remove(0);
     // TODO save body to web archive
     // TODO save body to safe network
-    // parse omni balances
-    let obalances: Vec<OMaidBalance> = serde_json::from_str(&obody).unwrap();
-    obalances
+    (snapshot, source_balances.remove(0))
 }
 
-fn add_public_keys(balances: &Vec<OMaidBalance>) -> Vec<OMaidBalance> {
+// The first run saves the snapshot with the distribution output and records
+// it in the journal. Later runs must use the same snapshot.
+fn check_journal_snapshot(journal: &mut Journal, snapshot: &Snapshot, run_dir: &Path) {
+    let snapshot_filepath = run_dir.join(SNAPSHOT_FILE);
+    match journal.snapshot() {
+        None => {
+            snapshot.save(&snapshot_filepath);
+            journal.record(JournalEntry::Snapshot {
+                provenance: snapshot.provenance.clone(),
+            });
+        }
+        Some(started) => {
+            if started.sha256 == snapshot.provenance.sha256 {
+                return;
+            }
+            println!("This distribution was started with a different snapshot");
+            println!("Started with block {} sha256 {}", started.block_height, started.sha256);
+            println!("This run has block {} sha256 {}", snapshot.provenance.block_height, snapshot.provenance.sha256);
+            println!("To continue, run again with --snapshot {}", snapshot_filepath.display());
+            process::exit(1);
+        }
+    }
+}
+
+// Keys are checked against the address when they're loaded so a bad key file
+// can't stop a run part way through. Rejected keys are returned with the
+// reason so they can be reported and fixed.
+fn add_public_keys(balances: &Vec<OMaidBalance>) -> (Vec<OMaidBalance>, Vec<RejectedKey>) {
     let mut pubkey_balances = Vec::<OMaidBalance>::new();
+    let mut rejected_keys = Vec::<RejectedKey>::new();
     // look in directory for files where
     // filename is base56 bitcoin address
     // filecontent is hex public key
     let mut keys_path = env::current_dir().unwrap();
     keys_path.push("keys");
     let metadata = fs::metadata(&keys_path);
-    if !metadata.is_ok() || !metadata.unwrap().is_dir() {
+    if metadata.is_err() || !metadata.unwrap().is_dir() {
         println!("keys directory containing public keys does not exist:");
         println!("{}", keys_path.display());
         process::exit(1);
@@ -113,96 +338,99 @@ fn add_public_keys(balances: &Vec<OMaidBalance>) -> Vec<OMaidBalance> {
         if !pk_path.exists() {
             continue;
         }
-        let mut file = fs::File::open(pk_path).unwrap();
-        let mut body = String::new();
-        file.read_to_string(&mut body).unwrap();
+        let body = match fs::read_to_string(&pk_path) {
+            Ok(b) => b,
+            Err(e) => {
+                rejected_keys.push(RejectedKey {
+                    address: balance.address.clone(),
+                    reason: format!("Unable to read {}: {}", pk_path.display(), e),
+                });
+                continue;
+            }
+        };
+        let pk_hex = match validate_public_key(&balance.address, &body) {
+            Ok(pk_hex) => pk_hex,
+            Err(e) => {
+                rejected_keys.push(RejectedKey {
+                    address: balance.address.clone(),
+                    reason: e,
+                });
+                continue;
+            }
+        };
         let pk_balance = OMaidBalance{
             address: balance.address.clone(),
             balance: balance.balance.clone(),
             reserved: balance.reserved.clone(),
-            public_key: Some(body),
+            public_key: Some(pk_hex),
         };
         pubkey_balances.push(pk_balance);
     }
-    pubkey_balances
+    (pubkey_balances, rejected_keys)
 }
 
-fn load_tokens_into_distribution_wallet(amount_maid: u32) {
-    // This uses the existing faucet functionality.
-    // This doesn't use the server function of the faucet
-    // because that will only issue 100 tokens at a time.
-    // It uses the faucet binary
-    // so must be run on the same machine as the faucet
-    // with the faucet binary on $PATH.
-    // TODO remove unwraps below
-    let sk_vec = hex::decode(WALLET_SECRET_KEY).unwrap();
-    let sk_bytes: [u8; 32] = sk_vec.as_slice().try_into().unwrap();
-    let sk = SecretKey::from_bytes(sk_bytes).unwrap();
+fn report_rejected_keys(rejected_keys: &[RejectedKey]) {
+    let mut report = format!("Rejected public key files: {}\n", rejected_keys.len());
+    for r in rejected_keys {
+        report += &format!("keys/{}: {}\n", r.address, r.reason);
+    }
+    print!("{}", report);
+    let mut file = fs::File::create(REJECTED_KEYS_FILE).unwrap();
+    file.write_all(report.as_bytes()).unwrap();
+    println!("Rejected keys saved to {}", REJECTED_KEYS_FILE);
+}
+
+fn load_tokens_into_distribution_wallet(backend: &mut dyn Backend, journal: &mut Journal, total_maid: u32) {
+    // a transfer from the faucet that a previous run didn't receive
+    if let Some((amount_maid, transfer_hex)) = journal.faucet_transfer().cloned() {
+        println!("Receiving {} tokens the faucet sent in a previous run", amount_maid);
+        receive_faucet_transfer(backend, journal, amount_maid, &transfer_hex);
+    }
+    // a resumed run only needs whatever the previous runs didn't fetch
+    let funded_maid = journal.funded();
+    if funded_maid >= total_maid {
+        println!("Wallet already has {} tokens from a previous run", funded_maid);
+        return;
+    }
+    let amount_maid = total_maid - funded_maid;
+    let sk = secret_key_from_hex(WALLET_SECRET_KEY);
     let pk = sk.public_key();
     let pk_hex = hex::encode(pk.to_bytes());
     println!("Getting {} tokens from faucet", amount_maid);
-    // the command is:
-    // faucet send amount to
-    let faucet_output = Command::new("faucet")
-        .args(["send", &amount_maid.to_string(), &pk_hex])
-        .output()
-        .unwrap();
-    if !faucet_output.status.success() {
-        println!("FAUCET STDOUT:\n{}", String::from_utf8_lossy(&faucet_output.stdout));
-        println!("FAUCET STDERR:\n{}", String::from_utf8_lossy(&faucet_output.stderr));
-        panic!("Failed to get from faucet, status {}", faucet_output.status);
-    }
-    // print any error
-    let stderr_bytes = faucet_output.stderr;
-    if stderr_bytes.len() > 0 {
-        println!("Faucet error:");
-        println!("{}", String::from_utf8_lossy(&stderr_bytes));
-    }
-    // get the transfer from the output of the faucet
-    let stdout_bytes = faucet_output.stdout;
-    let stdout = String::from_utf8_lossy(&stdout_bytes);
-    let lines = stdout.split("\n");
-    let mut transfer_hex = "";
-    for line in lines {
-        if line.len() > 100 && hex::decode(line).is_ok() {
-            transfer_hex = line;
-        }
-    }
-    if transfer_hex.len() == 0 {
-        panic!("Empty transfer from faucet");
-    }
+    let transfer_hex = match backend.fund_wallet(amount_maid, &pk_hex) {
+        Ok(t) => t,
+        Err(e) => panic!("Failed to get from faucet: {}", e),
+    };
+    journal.record(JournalEntry::FaucetTransfer {
+        amount: amount_maid,
+        transfer: transfer_hex.clone(),
+    });
+    receive_faucet_transfer(backend, journal, amount_maid, &transfer_hex);
+}
+
+fn receive_faucet_transfer(backend: &mut dyn Backend, journal: &mut Journal, amount_maid: u32, transfer_hex: &str) {
     // use our secret key for the cli wallet
     println!("Creating wallet with our sk");
-    let wallet_create_output = Command::new("safe")
-        .args(["wallet", "create", &WALLET_SECRET_KEY])
-        .output()
-        .unwrap();
-    if !wallet_create_output.status.success() {
-        println!("CREATE STDOUT:\n{}", String::from_utf8_lossy(&wallet_create_output.stdout));
-        println!("CREATE STDERR:\n{}", String::from_utf8_lossy(&wallet_create_output.stderr));
-        panic!("Failed to create wallet, status {}", wallet_create_output.status);
+    if let Err(e) = backend.create_wallet(WALLET_SECRET_KEY) {
+        panic!("Failed to create wallet: {}", e);
     }
     println!("Receiving transfer to our wallet");
-    // receive the transfer using the cli
-    let wallet_receive_output = Command::new("safe")
-        .args(["wallet", "receive", &transfer_hex])
-        .output()
-        .unwrap();
-    if !wallet_receive_output.status.success() {
-        println!("RECEIVE STDOUT:\n{}", String::from_utf8_lossy(&wallet_receive_output.stdout));
-        println!("RECEIVE STDERR:\n{}", String::from_utf8_lossy(&wallet_receive_output.stderr));
-        panic!("Failed to receive transfer, status {}", wallet_receive_output.status);
-    }
-    println!("RECEIVE STDOUT:\n{}", String::from_utf8_lossy(&wallet_receive_output.stdout));
+    if let Err(e) = backend.receive(transfer_hex) {
+        panic!("Failed to receive transfer: {}", e);
+    }
+    journal.record(JournalEntry::Funded {
+        amount: amount_maid,
+    });
 }
 
-fn maid_distribution_filepath(maid_address: String) -> PathBuf {
-    Path::new(ENCRYPTED_MD_DIR).join(maid_address)
+fn maid_distribution_filepath(run_dir: &Path, maid_address: String) -> PathBuf {
+    run_dir.join(ENCRYPTED_MD_DIR).join(maid_address)
 }
 
-fn distribute_tokens(balances: &Vec<OMaidBalance>) {
+fn distribute_tokens(backend: &mut dyn Backend, run_dir: &Path, journal: &mut Journal, plan: &DistributionPlan) {
     let mut all_encrypted_maid_distributions_csv = "MAID address,Distribution\n".to_string();
-    for b in balances {
+    let mut all_published = true;
+    for b in &plan.balances() {
         // check it has a public key
         if b.public_key.is_none() {
             continue;
@@ -212,106 +440,239 @@ fn distribute_tokens(balances: &Vec<OMaidBalance>) {
         if balance == 0 {
             continue;
         }
-        // check if this has already been distributed
-        let _ = fs::create_dir(ENCRYPTED_MD_DIR);
-        let md_filepath = maid_distribution_filepath(b.address.clone());
-        let mut encrypted_md_hex = String::new();
-        if md_filepath.exists() {
-            // read existing MaidDistribution from file
-            let mut file = fs::File::open(md_filepath.clone()).unwrap();
-            file.read_to_string(&mut encrypted_md_hex).unwrap();
-        }
-        else {
-            // create new encrypted MaidDistribution for this maid address
-            println!("Creating distribution of {} tokens for {}", b.balance.clone(), b.address.clone());
-            encrypted_md_hex = create_new_maid_distribution(b);
+        // create the encrypted MaidDistribution for this maid address,
+        // resuming from wherever a previous run stopped
+        let encrypted_md_hex = create_maid_distribution(backend, run_dir, journal, plan, b);
+        if !journal.progress(&b.address).unwrap().published {
+            all_published = false;
         }
         // keep track of the upload location and the maid address
         let row = format!("{},{}\n", b.address, encrypted_md_hex);
         all_encrypted_maid_distributions_csv += &row;
     }
     // save all_encrypted_maid_distributions_csv
-    let csv_filepath = maid_distribution_filepath("all_distributions.csv".to_string());
-    let mut file = fs::File::create(csv_filepath.clone()).unwrap();
-    let _ = file.write_all(&all_encrypted_maid_distributions_csv.as_bytes());
-    // upload the list of addresses -> encrypted MaidDistribution
-    let upload_output = Command::new("safe")
-        .args(["files", "upload", &csv_filepath.as_os_str().to_str().unwrap()])
-        .output()
-        .unwrap();
-    if !upload_output.status.success() {
-        println!("UPLOAD STDOUT:\n{}", String::from_utf8_lossy(&upload_output.stdout));
-        println!("UPLOAD STDERR:\n{}", String::from_utf8_lossy(&upload_output.stderr));
-        panic!("Failed to upload MaidDistribution, status {}", upload_output.status);
-    }
-    let upload_stdout_bytes = upload_output.stdout;
-    let upload_stdout = String::from_utf8_lossy(&upload_stdout_bytes);
-    // get the address of the uploaded data
-    let words = upload_stdout.split_whitespace();
-    let mut csv_address = "";
-    for word in words {
-        if word.len() == 64 && hex::decode(word).is_ok() {
-            csv_address = word;
+    let csv_filepath = maid_distribution_filepath(run_dir, "all_distributions.csv".to_string());
+    distribution_core::write_atomic(&csv_filepath, all_encrypted_maid_distributions_csv.as_bytes()).unwrap();
+    // no need to upload again if a previous run already published every
+    // distribution in this list
+    if all_published {
+        if let Some(csv_address) = journal.csv_address() {
+            println!("All distributions were already published");
+            println!("Address for distribution csv: {}", csv_address);
+            return;
         }
     }
-    if csv_address.len() == 0 {
-        panic!("No address for uploaded MaidDistribution list");
-    }
+    // upload the list of addresses -> encrypted MaidDistribution
+    let csv_address = match backend.upload(&csv_filepath) {
+        Ok(a) => a,
+        Err(e) => panic!("Failed to upload MaidDistribution list: {}", e),
+    };
+    journal.record(JournalEntry::Published {
+        csv_address: csv_address.clone(),
+    });
     // print out the location of that mapping
     println!("Address for distribution csv: {}", csv_address);
 }
 
-fn create_new_maid_distribution(b: &OMaidBalance) -> String {
+// Each step is recorded in the journal before moving to the next one so the
+// recipient secret key is on disk before any funds are sent, and a transfer
+// is never made twice for the same address.
+fn create_maid_distribution(backend: &mut dyn Backend, run_dir: &Path, journal: &mut Journal, plan: &DistributionPlan, b: &OMaidBalance) -> String {
+    let maid_pk_hex = b.public_key.clone().unwrap();
+    if journal.progress(&b.address).is_none() {
+        journal.record(JournalEntry::Planned {
+            address: b.address.clone(),
+            amount: b.balance.clone(),
+            public_key: maid_pk_hex.clone(),
+        });
+        // distributions created before the journal existed are only on disk
+        let md_filepath = maid_distribution_filepath(run_dir, b.address.clone());
+        if md_filepath.exists() {
+            let mut encrypted_md_hex = String::new();
+            let mut file = fs::File::open(md_filepath).unwrap();
+            file.read_to_string(&mut encrypted_md_hex).unwrap();
+            journal.record(JournalEntry::Encrypted {
+                address: b.address.clone(),
+                encrypted_md: encrypted_md_hex,
+            });
+        }
+    }
+    let progress = journal.progress(&b.address).unwrap().clone();
+    if let Some(encrypted_md_hex) = progress.encrypted_md {
+        // a run that stopped after the journal entry may not have saved the
+        // file
+        save_maid_distribution(run_dir, &b.address, &encrypted_md_hex);
+        return encrypted_md_hex;
+    }
+    if progress.amount != b.balance || progress.public_key != maid_pk_hex {
+        panic!("Distribution for {} was planned as {} tokens to {} but is now {} tokens to {}",
+            b.address, progress.amount, progress.public_key, b.balance, maid_pk_hex);
+    }
     // Generate random key for the maid user to use for spending.
     // This key should be generated from dkg, so this step will change in
     // the future.
     // For testnets, so long as the recipient key is never stored or known
     // the process is safe enough.
-    let recipient_sk = SecretKey::random();
-    let recipient_pk = recipient_sk.public_key();
-    let recipient_pk_hex = hex::encode(recipient_pk.to_bytes());
+    // The key is kept in the journal until the distribution is encrypted so
+    // it can't be lost if the run stops after the transfer is made.
+    let recipient_sk = match progress.secret_key {
+        Some(sk_hex) => secret_key_from_hex(&sk_hex),
+        None => {
+            let sk = SecretKey::random();
+            journal.record(JournalEntry::KeyGenerated {
+                address: b.address.clone(),
+                secret_key: hex::encode(sk.to_bytes()),
+            });
+            sk
+        }
+    };
     // generate a transfer to this public key
-    let wallet_send_output = Command::new("safe")
-        .args(["wallet", "send", &b.balance, &recipient_pk_hex])
-        .output()
-        .unwrap();
-    if !wallet_send_output.status.success() {
-        println!("SEND STDOUT:\n{}", String::from_utf8_lossy(&wallet_send_output.stdout));
-        println!("SEND STDERR:\n{}", String::from_utf8_lossy(&wallet_send_output.stderr));
-        panic!("Failed to send transfer, status {}", wallet_send_output.status);
-    }
-    let stdout_bytes = wallet_send_output.stdout;
-    let stdout = String::from_utf8_lossy(&stdout_bytes);
-    let lines = stdout.split("\n");
-    let mut transfer_hex = "";
-    for line in lines {
-        if line.len() > 100 && hex::decode(line).is_ok() {
-            transfer_hex = line;
+    let transfer_hex = match progress.transfer {
+        Some(t) => t,
+        None => {
+            if progress.sending {
+                println!("A previous run started sending to {} but did not record the result.", b.address);
+                println!("Check the wallet to see if the transfer was made, then add one of these lines to {}", journal.path().display());
+                println!("{{\"state\":\"sent\",\"address\":\"{}\",\"transfer\":\"<transfer hex>\"}}", b.address);
+                println!("{{\"state\":\"send_failed\",\"address\":\"{}\"}}", b.address);
+                process::exit(1);
+            }
+            println!("Creating distribution of {} tokens for {}", b.balance.clone(), b.address.clone());
+            journal.record(JournalEntry::Sending {
+                address: b.address.clone(),
+            });
+            let recipient_pk_hex = hex::encode(recipient_sk.public_key().to_bytes());
+            let transfer_hex = match backend.send(&b.balance, &recipient_pk_hex) {
+                Ok(t) => t,
+                Err(SendError::NotSent(e)) => {
+                    journal.record(JournalEntry::SendFailed {
+                        address: b.address.clone(),
+                    });
+                    panic!("Failed to send to {}: {}", b.address, e);
+                }
+                // left in the sending state so the next run asks the
+                // operator to check the wallet
+                Err(SendError::Unknown(e)) => {
+                    println!("{}", e);
+                    println!("Check the wallet to see if the transfer to {} was made before running again", b.address);
+                    process::exit(1);
+                }
+            };
+            journal.record(JournalEntry::Sent {
+                address: b.address.clone(),
+                transfer: transfer_hex.clone(),
+            });
+            transfer_hex
         }
-    }
-    if transfer_hex.len() == 0 {
-        panic!("Empty transfer to {}", b.address);
-    }
+    };
     let transfer_bytes = hex::decode(transfer_hex).unwrap();
     // create a MaidDistribution using this information
-    let md = MaidDistribution{
-        transfer: transfer_bytes.clone(),
-        secret_key: recipient_sk.to_bytes().to_vec(),
-    };
-    // encode the MD using messagepack
-    let md_bytes = rmp_serde::to_vec(&md).unwrap();
+    let md = MaidDistribution::new(
+        transfer_bytes.clone(),
+        recipient_sk.to_bytes().to_vec(),
+        &b.address,
+        b.balance.parse::<u64>().unwrap(),
+        plan.snapshot.block_height,
+        &plan.network_id,
+    );
+    // encode the MD using messagepack and
     // encrypt the messagepack bytes using ECIES and bitcoin public key
-    let maid_pk_hex = b.public_key.clone().unwrap();
     let maid_pk_bytes = hex::decode(maid_pk_hex).unwrap();
-    let encrypted_md = ecies::encrypt(&maid_pk_bytes, &md_bytes).unwrap();
+    let encrypted_md = md.encrypt(&maid_pk_bytes).unwrap();
     let encrypted_md_hex = hex::encode(encrypted_md);
-    // save encrypted md to file
-    let md_filepath = maid_distribution_filepath(b.address.clone());
-    let mut file = fs::File::create(md_filepath.clone()).unwrap();
-    let _ = file.write_all(&encrypted_md_hex.as_bytes());
+    journal.record(JournalEntry::Encrypted {
+        address: b.address.clone(),
+        encrypted_md: encrypted_md_hex.clone(),
+    });
+    save_maid_distribution(run_dir, &b.address, &encrypted_md_hex);
     encrypted_md_hex
 }
 
+// The file is replaced in one rename so it's never left partly written
+fn save_maid_distribution(run_dir: &Path, address: &str, encrypted_md_hex: &str) {
+    let md_filepath = maid_distribution_filepath(run_dir, address.to_string());
+    if fs::read_to_string(&md_filepath).is_ok_and(|h| h == encrypted_md_hex) {
+        return;
+    }
+    if let Err(e) = distribution_core::write_atomic(&md_filepath, encrypted_md_hex.as_bytes()) {
+        panic!("Failed to save distribution for {} to {}: {}", address, md_filepath.display(), e);
+    }
+}
+
+fn secret_key_from_hex(sk_hex: &str) -> SecretKey {
+    let sk_vec = hex::decode(sk_hex).unwrap();
+    let sk_bytes: [u8; 32] = sk_vec.as_slice().try_into().unwrap();
+    SecretKey::from_bytes(sk_bytes).unwrap()
+}
+
+// Decrypts the distribution for the test address and checks the transfer
+// inside it was made by the mock backend for the right amount and recipient.
+fn check_mock_distribution(mock_backend: &MockBackend, run_dir: &Path) -> Result<(), String> {
+    let md_filepath = maid_distribution_filepath(run_dir, TEST_BITCOIN_ADDRESS.to_string());
+    let encrypted_md_hex = match fs::read_to_string(&md_filepath) {
+        Ok(h) => h,
+        Err(e) => return Err(format!("Unable to read {}: {}", md_filepath.display(), e)),
+    };
+    let test_sk = bitcoin::PrivateKey::from_wif(TEST_BITCOIN_SECRET_KEY).unwrap();
+    let md = match hex::decode(encrypted_md_hex).map(|e| MaidDistribution::decrypt(&e, &test_sk.to_bytes())) {
+        Ok(Ok(md)) => md,
+        _ => return Err(format!("Mock distribution for {} can't be decrypted", TEST_BITCOIN_ADDRESS)),
+    };
+    let recipient_sk_bytes: [u8; 32] = match md.secret_key.as_slice().try_into() {
+        Ok(b) => b,
+        Err(_) => return Err(format!("Mock distribution for {} has an invalid secret key", TEST_BITCOIN_ADDRESS)),
+    };
+    let recipient_pk_hex = match SecretKey::from_bytes(recipient_sk_bytes) {
+        Ok(sk) => hex::encode(sk.public_key().to_bytes()),
+        Err(_) => return Err(format!("Mock distribution for {} has an invalid secret key", TEST_BITCOIN_ADDRESS)),
+    };
+    match mock_backend.transfers.get(&hex::encode(&md.transfer)) {
+        Some((1, pk_hex)) if *pk_hex == recipient_pk_hex => (),
+        _ => return Err(format!("Mock distribution for {} does not contain the expected transfer", TEST_BITCOIN_ADDRESS)),
+    }
+    if md.maid_address != TEST_BITCOIN_ADDRESS || md.amount != 1 {
+        return Err(format!("Mock distribution is for {} MAID at {}", md.amount, md.maid_address));
+    }
+    Ok(())
+}
+
+// Runs a whole distribution to the test address with the mock backend in a
+// temporary directory, from a snapshot made here so it never needs the network.
+fn check_mock_run() {
+    let run_dir = env::temp_dir().join(format!("distribute_mock_run_{}", process::id()));
+    let _ = fs::remove_dir_all(&run_dir);
+    let test_balance = OMaidBalance {
+        address: TEST_BITCOIN_ADDRESS.to_string(),
+        balance: "1".to_string(),
+        reserved: "0".to_string(),
+        public_key: None,
+    };
+    let body = serde_json::to_string(&vec![test_balance.clone()]).unwrap();
+    let snapshot = Snapshot::new("mock", 800000, body, "json");
+    let pubkey_balances = vec![OMaidBalance {
+        public_key: Some(TEST_BITCOIN_PUBLIC_KEY.to_string()),
+        ..test_balance
+    }];
+    let plan = create_plan(&snapshot.provenance, DEFAULT_NETWORK_ID, &snapshot.balances().unwrap(), &pubkey_balances, &[]);
+    let mut mock_backend = MockBackend::default();
+    // a run that stopped after the faucet sent the tokens but before they
+    // were received, the rerun must receive them without using the faucet
+    fs::create_dir_all(&run_dir).unwrap();
+    let wallet_pk_hex = hex::encode(secret_key_from_hex(WALLET_SECRET_KEY).public_key().to_bytes());
+    let transfer = mock_backend.fund_wallet(plan.total_from_faucet, &wallet_pk_hex).unwrap();
+    Journal::open(&run_dir.join(JOURNAL_FILE)).record(JournalEntry::FaucetTransfer {
+        amount: plan.total_from_faucet,
+        transfer,
+    });
+    run_distribution(&mut mock_backend, &run_dir, &snapshot, &plan);
+    let checked = check_mock_distribution(&mock_backend, &run_dir);
+    let _ = fs::remove_dir_all(&run_dir);
+    assert!(checked.is_ok(), "Mock run failed: {:?}", checked);
+    // uploads are free on the mock network so the upload amount is left
+    let left = mock_backend.balance;
+    assert!(left == plan.upload_amount as u64 && mock_backend.uploads.len() == 1, "Mock run left balance {} and uploaded {} files", left, mock_backend.uploads.len());
+}
+
 fn total_balance(balances: &Vec<OMaidBalance>) -> u32 {
     let mut total_maid = 0u32;
     for b in balances {
@@ -322,19 +683,22 @@ fn total_balance(balances: &Vec<OMaidBalance>) -> u32 {
     total_maid
 }
 
-fn fetch_from_cache_or_internet(url: &str) -> String {
+// key is usually a url but can be anything that identifies the response.
+// Errors aren't cached so they're fetched again next time.
+// cache_dir is CACHE_DIR except in tests.
+fn fetch_from_cache_or<F: FnOnce() -> Result<String, String>>(cache_dir: &Path, key: &str, fetch: F) -> Result<String, String> {
     // make directory for caching responses
-    let _ = fs::create_dir(CACHE_DIR);
-    // check if the url exists in the cache
-    let cached_body = get_cached_response(url);
-    if cached_body.len() > 0 {
-        return cached_body;
-    }
-    // make the request from the internet
-    let body = fetch_from_internet(url);
+    let _ = fs::create_dir(cache_dir);
+    // check if the key exists in the cache
+    let cached_body = get_cached_response(cache_dir, key);
+    if !cached_body.is_empty() {
+        return Ok(cached_body);
+    }
+    // make the request
+    let body = fetch()?;
     // save response body to cache
-    save_response_to_cache(url, body.clone());
-    body
+    save_response_to_cache(cache_dir, key, body.clone());
+    Ok(body)
 }
 
 fn fetch_from_internet(url: &str) -> String {
@@ -343,13 +707,13 @@ fn fetch_from_internet(url: &str) -> String {
     response.as_str().unwrap().to_string()
 }
 
-fn cache_filename(url: &str) -> PathBuf {
+fn cache_filename(cache_dir: &Path, url: &str) -> PathBuf {
     let url_hash = sha256::digest(url);
-    Path::new(CACHE_DIR).join(url_hash)
+    cache_dir.join(url_hash)
 }
 
-fn get_cached_response(url: &str) -> String {
-    let filename = cache_filename(url);
+fn get_cached_response(cache_dir: &Path, url: &str) -> String {
+    let filename = cache_filename(cache_dir, url);
     let metadata = match fs::metadata(filename.clone()) {
         Ok(m) => m,
         Err(_) => return "".to_string(),
@@ -374,8 +738,8 @@ fn get_cached_response(url: &str) -> String {
     body
 }
 
-fn save_response_to_cache(url: &str, body: String) {
-    let filename = cache_filename(url);
+fn save_response_to_cache(cache_dir: &Path, url: &str, body: String) {
+    let filename = cache_filename(cache_dir, url);
     // write to cache
     let mut file = fs::File::create(filename).unwrap();
     file.write_all(body.as_bytes()).unwrap();
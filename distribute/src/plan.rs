@@ -0,0 +1,187 @@
+use crate::pubkeys::RejectedKey;
+use crate::snapshot::SnapshotProvenance;
+use crate::OMaidBalance;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// Need a little extra in the wallet to upload the data to the safe network
+const UPLOAD_AMOUNT: u32 = 1;
+
+// Everything the real run will do, so it can be reviewed before any tokens
+// are fetched from the faucet or sent.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct DistributionPlan {
+    pub snapshot: SnapshotProvenance,
+    // the safe network the tokens are sent on
+    pub network_id: String,
+    pub distributions: Vec<PlannedDistribution>,
+    pub skipped: Vec<SkippedAddress>,
+    pub total_addresses: usize,
+    pub total_distributions: usize,
+    pub total_skipped: usize,
+    pub distribution_amount: u32,
+    pub upload_amount: u32,
+    pub total_from_faucet: u32,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct PlannedDistribution {
+    pub address: String,
+    pub amount: String,
+    pub public_key: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SkippedAddress {
+    pub address: String,
+    pub balance: String,
+    pub reason: String,
+}
+
+impl DistributionPlan {
+    pub fn balances(&self) -> Vec<OMaidBalance> {
+        self.distributions.iter().map(|d| OMaidBalance {
+            address: d.address.clone(),
+            balance: d.amount.clone(),
+            reserved: "0".to_string(),
+            public_key: Some(d.public_key.clone()),
+        }).collect()
+    }
+}
+
+// omaid_balances is every balance in the snapshot,
+// pubkey_balances is the balances that have a public key,
+// rejected_keys is the addresses with a key file that failed validation.
+pub fn create_plan(snapshot: &SnapshotProvenance, network_id: &str, omaid_balances: &Vec<OMaidBalance>, pubkey_balances: &Vec<OMaidBalance>, rejected_keys: &[RejectedKey]) -> DistributionPlan {
+    let mut distributions = Vec::<PlannedDistribution>::new();
+    let mut skipped = Vec::<SkippedAddress>::new();
+    let with_pubkey: HashSet<&String> = pubkey_balances.iter().map(|b| &b.address).collect();
+    let with_rejected_key: HashSet<&String> = rejected_keys.iter().map(|r| &r.address).collect();
+    for b in omaid_balances {
+        if !with_pubkey.contains(&b.address) {
+            let reason = match with_rejected_key.contains(&b.address) {
+                true => "invalid public key",
+                false => "no public key",
+            };
+            skipped.push(SkippedAddress {
+                address: b.address.clone(),
+                balance: b.balance.clone(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+    let mut planned_balances = Vec::<OMaidBalance>::new();
+    for b in pubkey_balances {
+        let reason = match b.balance.parse::<u32>() {
+            Ok(0) => "zero balance",
+            Ok(_) => "",
+            Err(_) => "invalid balance",
+        };
+        if !reason.is_empty() {
+            skipped.push(SkippedAddress {
+                address: b.address.clone(),
+                balance: b.balance.clone(),
+                reason: reason.to_string(),
+            });
+            continue;
+        }
+        distributions.push(PlannedDistribution {
+            address: b.address.clone(),
+            amount: b.balance.clone(),
+            public_key: b.public_key.clone().unwrap(),
+        });
+        planned_balances.push(OMaidBalance {
+            address: b.address.clone(),
+            balance: b.balance.clone(),
+            reserved: b.reserved.clone(),
+            public_key: b.public_key.clone(),
+        });
+    }
+    let distribution_amount = crate::total_balance(&planned_balances);
+    DistributionPlan {
+        snapshot: snapshot.clone(),
+        network_id: network_id.to_string(),
+        total_addresses: omaid_balances.len(),
+        total_distributions: distributions.len(),
+        total_skipped: skipped.len(),
+        distributions,
+        skipped,
+        distribution_amount,
+        upload_amount: UPLOAD_AMOUNT,
+        total_from_faucet: distribution_amount + UPLOAD_AMOUNT,
+    }
+}
+
+pub fn print_plan_summary(plan: &DistributionPlan) {
+    println!("Network: {}", plan.network_id);
+    println!("Distributions: {}", plan.total_distributions);
+    println!("Skipped: {}", plan.total_skipped);
+    for reason in ["no public key", "invalid public key", "zero balance", "invalid balance"] {
+        let count = plan.skipped.iter().filter(|s| s.reason == reason).count();
+        if count > 0 {
+            println!("    {}: {}", reason, count);
+        }
+    }
+    println!("Total to be distributed: {}", plan.distribution_amount);
+    println!("Total from faucet including upload costs: {}", plan.total_from_faucet);
+}
+
+// Returns the sha256 of the saved file so it can be recorded when the plan
+// is signed off.
+pub fn save_plan(plan: &DistributionPlan, path: &Path) -> String {
+    let body = serde_json::to_string_pretty(plan).unwrap();
+    let mut file = fs::File::create(path).unwrap();
+    file.write_all(body.as_bytes()).unwrap();
+    sha256::digest(body)
+}
+
+// The snapshot a plan was made from is saved next to it, eg
+// distribution_plan.snapshot.json, so the run uses the same balances.
+pub fn plan_snapshot_path(plan_path: &Path) -> PathBuf {
+    plan_path.with_extension("snapshot.json")
+}
+
+// Returns an error if the plan it's about to carry out is not the same as the
+// plan that was approved, after printing the differences.
+pub fn check_approved_plan(plan: &DistributionPlan, path: &Path) -> Result<(), String> {
+    let mut body = String::new();
+    let mut file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Unable to open approved plan {}: {}", path.display(), e)),
+    };
+    if let Err(e) = file.read_to_string(&mut body) {
+        return Err(format!("Unable to read approved plan {}: {}", path.display(), e));
+    }
+    println!("Approved plan sha256: {}", sha256::digest(body.clone()));
+    let approved: DistributionPlan = match serde_json::from_str(&body) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Invalid approved plan {}: {}", path.display(), e)),
+    };
+    if approved == *plan {
+        println!("Distribution matches the approved plan");
+        return Ok(());
+    }
+    println!("Distribution does not match the approved plan {}", path.display());
+    if approved.snapshot != plan.snapshot {
+        println!("Approved plan is for snapshot at block {} sha256 {}", approved.snapshot.block_height, approved.snapshot.sha256);
+        println!("Run again using the snapshot saved with the plan, {}", plan_snapshot_path(path).display());
+    }
+    if approved.network_id != plan.network_id {
+        println!("Approved plan is for network {} but this run is for {}", approved.network_id, plan.network_id);
+    }
+    let approved_rows: HashSet<_> = approved.distributions.iter().map(|d| (&d.address, &d.amount, &d.public_key)).collect();
+    let planned_rows: HashSet<_> = plan.distributions.iter().map(|d| (&d.address, &d.amount, &d.public_key)).collect();
+    for (address, amount, _) in planned_rows.difference(&approved_rows) {
+        println!("Not approved: {} tokens to {}", amount, address);
+    }
+    for (address, amount, _) in approved_rows.difference(&planned_rows) {
+        println!("Approved but not planned: {} tokens to {}", amount, address);
+    }
+    if approved.total_from_faucet != plan.total_from_faucet {
+        println!("Approved total {} but planned total {}", approved.total_from_faucet, plan.total_from_faucet);
+    }
+    Err(format!("Distribution does not match the approved plan {}", path.display()))
+}
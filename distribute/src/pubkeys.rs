@@ -0,0 +1,95 @@
+use crate::OMaidBalance;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Address, Network, PublicKey, ScriptBuf, TxIn};
+use distribution_core::{addresses_for_public_key, validate_bitcoin_pair, write_atomic};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// A file in the keys directory that can't be used for a distribution
+pub struct RejectedKey {
+    pub address: String,
+    pub reason: String,
+}
+
+// Checks the public key is valid and is for the address, the same check the
+// public key server makes before accepting a key.
+// Returns the key as lowercase hex without any whitespace.
+pub fn validate_public_key(address: &str, pk_hex: &str) -> Result<String, String> {
+    let pk_hex = pk_hex.trim().to_lowercase();
+    if pk_hex.is_empty() {
+        return Err("Empty public key".to_string());
+    }
+    match validate_bitcoin_pair(address, &pk_hex) {
+        Ok(()) => Ok(pk_hex),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Every address in the snapshot by its output script, so keys found on chain
+// can be matched without encoding each one as an address.
+pub struct AddressSet {
+    scripts: HashMap<ScriptBuf, String>,
+}
+
+impl AddressSet {
+    pub fn new(balances: &Vec<OMaidBalance>) -> AddressSet {
+        let mut scripts = HashMap::<ScriptBuf, String>::new();
+        for b in balances {
+            let address = match Address::from_str(&b.address) {
+                Ok(a) => a,
+                Err(_) => continue,
+            };
+            if let Ok(address) = address.require_network(Network::Bitcoin) {
+                scripts.insert(address.script_pubkey(), b.address.clone());
+            }
+        }
+        AddressSet { scripts }
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    // The snapshot addresses that are controlled by this public key.
+    // The script is derived from the key so any match is a key for that
+    // address, there's no need to check a signature.
+    pub fn addresses_for(&self, pk: &PublicKey) -> Vec<String> {
+        addresses_for_public_key(pk).iter().filter_map(|a| self.scripts.get(&a.script_pubkey()).cloned()).collect()
+    }
+}
+
+// Public keys revealed by spending an input.
+// p2pkh puts the key as the last push of the scriptSig.
+// p2sh-p2wpkh and p2wpkh put it as the second witness item.
+pub fn public_keys_in_input(input: &TxIn) -> Vec<PublicKey> {
+    let mut pks = Vec::<PublicKey>::new();
+    if input.witness.len() == 2 {
+        if let Some(pk) = input.witness.nth(1).and_then(|b| PublicKey::from_slice(b).ok()) {
+            pks.push(pk);
+        }
+    }
+    let last_push = input.script_sig.instructions().filter_map(|i| match i {
+        Ok(Instruction::PushBytes(b)) => Some(b.as_bytes().to_vec()),
+        _ => None,
+    }).last();
+    if let Some(pk) = last_push.and_then(|b| PublicKey::from_slice(&b).ok()) {
+        pks.push(pk);
+    }
+    pks
+}
+
+// Writes the key in the same format as keys submitted by users.
+// Returns false if there's already a key for this address, which is left as is.
+pub fn save_public_key(keys_dir: &Path, address: &str, pk: &PublicKey) -> bool {
+    // never write a key that doesn't belong to the address
+    if let Err(e) = validate_bitcoin_pair(address, &pk.to_string()) {
+        panic!("Public key {} is not for address {}: {}", pk, address, e);
+    }
+    let pk_path = keys_dir.join(address);
+    if pk_path.exists() {
+        return false;
+    }
+    write_atomic(&pk_path, pk.to_string().as_bytes()).unwrap();
+    true
+}
@@ -0,0 +1,93 @@
+use crate::args::Args;
+use crate::pubkeys::validate_public_key;
+use distribution_core::{canonical_address, write_atomic};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+// Token for the public_key_server admin api, the same variable the server
+// reads it from
+const ADMIN_TOKEN_ENV: &str = "ADMIN_TOKEN";
+// The export is every key so can take a while
+const PULL_TIMEOUT_SECS: u64 = 300;
+
+// Downloads the keys from public_key_server into the keys directory.
+// Keys already in the keys directory are left as they are, the same as keys
+// found by scan-keys.
+pub fn pull_keys(args: &Args) {
+    let server = match args.value("server") {
+        Some(s) => s.trim_end_matches('/').to_string(),
+        None => {
+            println!("pull-keys needs --server <public_key_server url>");
+            process::exit(1);
+        }
+    };
+    let token = match env::var(ADMIN_TOKEN_ENV) {
+        Ok(t) if !t.trim().is_empty() => t.trim().to_string(),
+        _ => {
+            println!("pull-keys needs the admin token in {}", ADMIN_TOKEN_ENV);
+            process::exit(1);
+        }
+    };
+    let mut url = format!("{}/admin/v1/export?format=jsonl", server);
+    if let Some(round) = args.value("round") {
+        url = format!("{}&round={}", url, round);
+    }
+    println!("Fetching keys from {}", url);
+    let response = minreq::get(url.as_str())
+        .with_header("Authorization", format!("Bearer {}", token))
+        .with_timeout(PULL_TIMEOUT_SECS)
+        .send();
+    let response = match response {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Unable to fetch keys: {}", e);
+            process::exit(1);
+        }
+    };
+    let body = response.as_str().unwrap_or("");
+    if response.status_code != 200 {
+        println!("Error fetching keys, status {}: {}", response.status_code, body);
+        process::exit(1);
+    }
+    let keys_dir = Path::new("keys");
+    fs::create_dir_all(keys_dir).unwrap();
+    let mut saved = 0;
+    let mut existing = 0;
+    let mut rejected = 0;
+    for line in body.lines().filter(|l| !l.trim().is_empty()) {
+        let key: Value = match serde_json::from_str(line) {
+            Ok(k) => k,
+            Err(_) => {
+                println!("Invalid line in export: {}", line);
+                rejected += 1;
+                continue;
+            }
+        };
+        let address = canonical_address(key["address"].as_str().unwrap_or(""));
+        let pk_hex = key["pkhex"].as_str().unwrap_or("");
+        // the address becomes a filename so it must be checked before use
+        let pk_hex = match validate_public_key(&address, pk_hex) {
+            Ok(pk) => pk,
+            Err(e) => {
+                println!("Not saving key for {}: {}", address, e);
+                rejected += 1;
+                continue;
+            }
+        };
+        let pk_path = keys_dir.join(&address);
+        if pk_path.exists() {
+            existing += 1;
+            continue;
+        }
+        write_atomic(&pk_path, pk_hex.as_bytes()).unwrap();
+        saved += 1;
+    }
+    println!("Saved {} new keys to {}", saved, keys_dir.display());
+    println!("{} keys were already in {}", existing, keys_dir.display());
+    if rejected > 0 {
+        println!("{} keys were not valid and not saved", rejected);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::args::Args;
+use crate::electrum::ElectrumClient;
+use crate::pubkeys::{public_keys_in_input, save_public_key, AddressSet};
+use crate::sources::{rpc_auth, RpcClient};
+use crate::OMaidBalance;
+use bitcoin::{Block, Transaction};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Mainnet magic bytes at the start of every block in blk*.dat
+const BLOCK_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+// Bitcoin Core 28 and later obfuscates block files with this key
+const XOR_FILE: &str = "xor.dat";
+// How often to print progress when scanning with rpc
+const PROGRESS_BLOCKS: u64 = 1000;
+
+// Keeps track of what has been found across every block scanned.
+struct KeyScan<'a> {
+    addresses: AddressSet,
+    keys_dir: &'a Path,
+    found: HashSet<String>,
+    saved: usize,
+    transactions: u64,
+}
+
+impl KeyScan<'_> {
+    fn scan_block(&mut self, block: &Block) {
+        for tx in &block.txdata {
+            self.scan_transaction(tx);
+        }
+    }
+
+    fn scan_transaction(&mut self, tx: &Transaction) {
+        self.transactions += 1;
+        if tx.is_coinbase() {
+            return;
+        }
+        for input in &tx.input {
+            for pk in public_keys_in_input(input) {
+                for address in self.addresses.addresses_for(&pk) {
+                    if !self.found.insert(address.clone()) {
+                        continue;
+                    }
+                    if save_public_key(self.keys_dir, &address, &pk) {
+                        println!("Found public key for {}", address);
+                        self.saved += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Looks through the blockchain for inputs spent from snapshot addresses and
+// saves the public key for each one to the keys directory. Keys that are
+// already in the keys directory are not changed.
+pub fn scan_public_keys(args: &Args, balances: &Vec<OMaidBalance>) -> Result<(), String> {
+    let keys_dir = Path::new("keys");
+    if let Err(e) = fs::create_dir_all(keys_dir) {
+        return Err(format!("Unable to create {}: {}", keys_dir.display(), e));
+    }
+    let mut scan = KeyScan {
+        addresses: AddressSet::new(balances),
+        keys_dir,
+        found: HashSet::new(),
+        saved: 0,
+        transactions: 0,
+    };
+    println!("Scanning for public keys of {} addresses", scan.addresses.len());
+    if let Some(blocks_dir) = args.value("blocks-dir") {
+        scan_block_files(&mut scan, Path::new(blocks_dir))?;
+    } else if let Some(url) = args.value("bitcoin-rpc") {
+        let rpc = RpcClient {
+            url: url.clone(),
+            auth: rpc_auth(args, "bitcoin-rpc", "BITCOIN_RPC_PASSWORD")?,
+        };
+        let from_height = match args.value("from-height") {
+            Some(h) => parse_height("from-height", h)?,
+            None => 0,
+        };
+        let to_height = match args.value("to-height") {
+            Some(h) => parse_height("to-height", h)?,
+            None => rpc.block_count()?,
+        };
+        scan_rpc(&mut scan, &rpc, from_height, to_height)?;
+    } else if let Some(server) = args.value("electrum") {
+        scan_electrum(&mut scan, &mut ElectrumClient::new(server), balances);
+    } else {
+        return Err("scan-keys needs --blocks-dir <dir>, --bitcoin-rpc <url> or --electrum <host:port>".to_string());
+    }
+    println!("Transactions scanned: {}", scan.transactions);
+    println!("Addresses with public keys on chain: {} of {}", scan.found.len(), scan.addresses.len());
+    println!("New public keys saved to {}: {}", keys_dir.display(), scan.saved);
+    Ok(())
+}
+
+fn parse_height(option: &str, height: &str) -> Result<u64, String> {
+    match height.parse::<u64>() {
+        Ok(h) => Ok(h),
+        Err(_) => Err(format!("Invalid --{} {}", option, height)),
+    }
+}
+
+// Reads every blk*.dat file in the Bitcoin Core blocks directory.
+// Blocks aren't in height order and may include stale blocks, which doesn't
+// matter since any key that matches an address is a key for that address.
+fn scan_block_files(scan: &mut KeyScan, blocks_dir: &Path) -> Result<(), String> {
+    let xor_key = fs::read(blocks_dir.join(XOR_FILE)).unwrap_or_default();
+    let entries = match fs::read_dir(blocks_dir) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Unable to read blocks directory {}: {}", blocks_dir.display(), e)),
+    };
+    let mut block_files = Vec::<PathBuf>::new();
+    for entry in entries {
+        let path = match entry {
+            Ok(e) => e.path(),
+            Err(e) => return Err(format!("Unable to read blocks directory {}: {}", blocks_dir.display(), e)),
+        };
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        if filename.starts_with("blk") && filename.ends_with(".dat") {
+            block_files.push(path);
+        }
+    }
+    block_files.sort();
+    if block_files.is_empty() {
+        return Err(format!("No blk*.dat files in {}", blocks_dir.display()));
+    }
+    for block_file in block_files {
+        println!("Scanning {}", block_file.display());
+        let mut data = match fs::read(&block_file) {
+            Ok(d) => d,
+            Err(e) => return Err(format!("Unable to read {}: {}", block_file.display(), e)),
+        };
+        if xor_key.iter().any(|b| *b != 0) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b ^= xor_key[i % xor_key.len()];
+            }
+        }
+        scan_block_file(scan, &block_file, &data);
+    }
+    Ok(())
+}
+
+// Each block is stored as magic, little endian size, block.
+// The end of the file may be preallocated zeroes.
+fn scan_block_file(scan: &mut KeyScan, block_file: &Path, data: &[u8]) {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        if data[pos..pos + 4] != BLOCK_MAGIC {
+            if data[pos..pos + 4] != [0, 0, 0, 0] {
+                println!("Unexpected bytes at {} in {}, skipping the rest of the file", pos, block_file.display());
+            }
+            return;
+        }
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        let end = start + size;
+        if end > data.len() {
+            println!("Incomplete block at {} in {}", pos, block_file.display());
+            return;
+        }
+        match bitcoin::consensus::deserialize::<Block>(&data[start..end]) {
+            Ok(block) => scan.scan_block(&block),
+            Err(e) => println!("Invalid block at {} in {}: {}", pos, block_file.display(), e),
+        }
+        pos = end;
+    }
+}
+
+fn scan_rpc(scan: &mut KeyScan, rpc: &RpcClient, from_height: u64, to_height: u64) -> Result<(), String> {
+    println!("Scanning blocks {} to {} from {}", from_height, to_height, rpc.url);
+    for height in from_height..=to_height {
+        // verbosity 0 gives the raw block hex
+        let fetched = rpc.call("getblockhash", json!([height]))
+            .and_then(|hash| rpc.call("getblock", json!([hash, 0])).map(|block| (hash, block)));
+        let (hash, block_hex) = match fetched {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Unable to fetch block {}: {}", height, e)),
+        };
+        let block_bytes = match block_hex.as_str().map(hex::decode) {
+            Some(Ok(b)) => b,
+            _ => return Err(format!("Block {} at height {} is not hex: {}", hash, height, block_hex)),
+        };
+        let block: Block = match bitcoin::consensus::deserialize(&block_bytes) {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Invalid block {} at height {}: {}", hash, height, e)),
+        };
+        scan.scan_block(&block);
+        if height % PROGRESS_BLOCKS == 0 {
+            println!("Scanned to block {}, found {} public keys", height, scan.found.len());
+        }
+    }
+    Ok(())
+}
+
+// Looks at the history of each address that doesn't have a key yet. Only
+// transactions that spend from the address reveal its key, but every
+// transaction is checked since it may spend from other snapshot addresses.
+fn scan_electrum(scan: &mut KeyScan, electrum: &mut ElectrumClient, balances: &Vec<OMaidBalance>) {
+    println!("Looking up address history from {}", electrum.server);
+    let mut checked_txids = HashSet::<String>::new();
+    for b in balances {
+        if scan.found.contains(&b.address) || scan.keys_dir.join(&b.address).exists() {
+            continue;
+        }
+        let txids = match electrum.address_history(&b.address) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("Unable to get history for {}: {}", b.address, e);
+                continue;
+            }
+        };
+        for txid in txids {
+            if !checked_txids.insert(txid.clone()) {
+                continue;
+            }
+            match electrum.transaction(&txid) {
+                Ok(tx) => scan.scan_transaction(&tx),
+                Err(e) => println!("Unable to get transaction {}: {}", txid, e),
+            }
+            if scan.found.contains(&b.address) {
+                break;
+            }
+        }
+    }
+}
+
+pub fn tests() {
+    use bitcoin::block::{Header, Version};
+    use bitcoin::hashes::Hash;
+    use bitcoin::script::{Builder, PushBytesBuf};
+    use bitcoin::{absolute, transaction, BlockHash, CompactTarget, OutPoint, PublicKey, ScriptBuf, Sequence, TxIn, TxMerkleNode, Txid, Witness};
+    use std::str::FromStr;
+    // p2pkh puts the key in the scriptSig and p2wpkh in the witness
+    let p2pkh_address = "1LyVLuxCbgLgYCZ6Sk6BrPJqAhixuyJpP7";
+    let p2pkh_pk = PublicKey::from_str("02888b3476298033f5f6ac52f868d603ace34de8918944a2ecde9b61e751132926").unwrap();
+    let p2wpkh_address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+    let p2wpkh_pk = PublicKey::from_str("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+    // signatures aren't checked so any bytes will do
+    let signature = vec![0x30u8; 71];
+    let spend = |vout: u32, script_sig: ScriptBuf, witness: Witness| TxIn {
+        previous_output: OutPoint {
+            txid: Txid::all_zeros(),
+            vout,
+        },
+        script_sig,
+        sequence: Sequence::MAX,
+        witness,
+    };
+    let p2pkh_script_sig = Builder::new()
+        .push_slice(PushBytesBuf::try_from(signature.clone()).unwrap())
+        .push_slice(PushBytesBuf::try_from(p2pkh_pk.to_bytes()).unwrap())
+        .into_script();
+    let p2pkh_input = spend(0, p2pkh_script_sig, Witness::new());
+    let p2wpkh_input = spend(1, ScriptBuf::new(), Witness::from_slice(&[signature.clone(), p2wpkh_pk.to_bytes()]));
+    assert!(public_keys_in_input(&p2pkh_input) == [p2pkh_pk], "p2pkh input gave {:?}", public_keys_in_input(&p2pkh_input));
+    assert!(public_keys_in_input(&p2wpkh_input) == [p2wpkh_pk], "p2wpkh input gave {:?}", public_keys_in_input(&p2wpkh_input));
+    let transaction = |input: Vec<TxIn>| Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input,
+        output: vec![],
+    };
+    // the coinbase has the p2pkh key in its script but isn't a spend
+    let coinbase_input = TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: Builder::new().push_slice(PushBytesBuf::try_from(p2pkh_pk.to_bytes()).unwrap()).into_script(),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+    };
+    let block = Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 0,
+        },
+        txdata: vec![transaction(vec![coinbase_input.clone()]), transaction(vec![p2pkh_input, p2wpkh_input])],
+    };
+    let coinbase_only = Block {
+        txdata: vec![transaction(vec![coinbase_input])],
+        ..block.clone()
+    };
+    // blk*.dat has magic, size and block, with zeroes preallocated at the end
+    let mut data = Vec::<u8>::new();
+    for b in [&coinbase_only, &block] {
+        let block_bytes = bitcoin::consensus::serialize(b);
+        data.extend_from_slice(&BLOCK_MAGIC);
+        data.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+        data.extend_from_slice(&block_bytes);
+    }
+    data.extend_from_slice(&[0u8; 64]);
+    let dir = std::env::temp_dir().join(format!("distribute_scan_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let blocks_dir = dir.join("blocks");
+    let keys_dir = dir.join("keys");
+    fs::create_dir_all(&blocks_dir).unwrap();
+    fs::create_dir_all(&keys_dir).unwrap();
+    // keys are saved for snapshot addresses spent from in the blocks
+    {
+        let unspent_address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        fs::write(blocks_dir.join("blk00000.dat"), &data).unwrap();
+        let balances: Vec<OMaidBalance> = [p2pkh_address, p2wpkh_address, unspent_address].iter().map(|a| OMaidBalance {
+            address: a.to_string(),
+            balance: "1".to_string(),
+            reserved: "0".to_string(),
+            public_key: None,
+        }).collect();
+        let mut scan = KeyScan {
+            addresses: AddressSet::new(&balances),
+            keys_dir: &keys_dir,
+            found: HashSet::new(),
+            saved: 0,
+            transactions: 0,
+        };
+        let result = scan_block_files(&mut scan, &blocks_dir);
+        assert!(result.is_ok(), "Scanning block files gave {:?}", result);
+        assert!(scan.transactions == 3, "Scanned {} transactions", scan.transactions);
+        assert!(scan.saved == 2, "Saved {} keys", scan.saved);
+        for (address, pk) in [(p2pkh_address, p2pkh_pk), (p2wpkh_address, p2wpkh_pk)] {
+            let saved = fs::read_to_string(keys_dir.join(address)).unwrap_or_default();
+            assert!(saved == pk.to_string(), "Key saved for {} is {}", address, saved);
+        }
+        assert!(!keys_dir.join(unspent_address).exists(), "Key saved for address that wasn't spent from");
+    }
+    // a missing or empty blocks directory is an error
+    {
+        let mut scan = KeyScan {
+            addresses: AddressSet::new(&vec![]),
+            keys_dir: &keys_dir,
+            found: HashSet::new(),
+            saved: 0,
+            transactions: 0,
+        };
+        assert!(scan_block_files(&mut scan, &dir.join("missing")).is_err(), "Missing blocks directory did not return error");
+        assert!(scan_block_files(&mut scan, &keys_dir).is_err(), "Directory without block files did not return error");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
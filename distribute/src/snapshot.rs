@@ -0,0 +1,118 @@
+use crate::OMaidBalance;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SNAPSHOT_FILE: &str = "snapshot.json";
+
+// Where a snapshot of balances came from, saved with every distribution so
+// it can be reproduced and so a run can't continue with different balances.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotProvenance {
+    pub source: String,
+    pub block_height: u64,
+    // unix seconds when the balances were fetched or the export was loaded.
+    // It's kept when the snapshot is saved and loaded again, but loading the
+    // same export twice gives different times so it isn't compared.
+    pub timestamp: u64,
+    // sha256 of the raw snapshot body
+    pub sha256: String,
+}
+
+// Snapshots are the same if they have the same balances for the same block,
+// whenever they were fetched.
+impl PartialEq for SnapshotProvenance {
+    fn eq(&self, other: &SnapshotProvenance) -> bool {
+        self.source == other.source && self.block_height == other.block_height && self.sha256 == other.sha256
+    }
+}
+
+// The raw body is kept exactly as received so the hash can be checked.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    pub provenance: SnapshotProvenance,
+    // json or csv
+    #[serde(default = "default_format")]
+    pub format: String,
+    pub body: String,
+}
+
+fn default_format() -> String {
+    "json".to_string()
+}
+
+// Rows of a csv snapshot, reserved is optional
+#[derive(Deserialize)]
+struct CsvBalance {
+    address: String,
+    balance: String,
+    #[serde(default)]
+    reserved: Option<String>,
+}
+
+impl Snapshot {
+    pub fn new(source: &str, block_height: u64, body: String, format: &str) -> Snapshot {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Snapshot {
+            provenance: SnapshotProvenance {
+                source: source.to_string(),
+                block_height,
+                timestamp,
+                sha256: sha256::digest(body.clone()),
+            },
+            format: format.to_string(),
+            body,
+        }
+    }
+
+    // Returns an error if the body has changed since the snapshot was taken
+    pub fn verify(&self) -> Result<(), String> {
+        let body_hash = sha256::digest(self.body.clone());
+        if body_hash != self.provenance.sha256 {
+            return Err(format!("Expected sha256 {} but body has sha256 {}", self.provenance.sha256, body_hash));
+        }
+        Ok(())
+    }
+
+    // Returns an error if the body isn't balances in the snapshot's format,
+    // eg a malformed export or an error page from omniexplorer.
+    pub fn balances(&self) -> Result<Vec<OMaidBalance>, String> {
+        if self.format == "csv" {
+            let mut reader = csv::Reader::from_reader(self.body.as_bytes());
+            let mut balances = Vec::<OMaidBalance>::new();
+            for (i, row) in reader.deserialize().enumerate() {
+                let row: CsvBalance = match row {
+                    Ok(r) => r,
+                    Err(e) => return Err(format!("Invalid balance on row {} of csv snapshot: {}", i + 1, e)),
+                };
+                balances.push(OMaidBalance {
+                    address: row.address,
+                    balance: row.balance,
+                    reserved: row.reserved.unwrap_or("0".to_string()),
+                    public_key: None,
+                });
+            }
+            return Ok(balances);
+        }
+        match serde_json::from_str(&self.body) {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!("Invalid balances in json snapshot: {}", e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) {
+        let body = serde_json::to_string(self).unwrap();
+        let mut file = fs::File::create(path).unwrap();
+        file.write_all(body.as_bytes()).unwrap();
+        file.sync_all().unwrap();
+    }
+
+    pub fn print(&self) {
+        println!("Snapshot source: {}", self.provenance.source);
+        println!("Snapshot block height: {}", self.provenance.block_height);
+        println!("Snapshot fetched at: {}", self.provenance.timestamp);
+        println!("Snapshot sha256: {}", self.provenance.sha256);
+    }
+}
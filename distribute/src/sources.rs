@@ -0,0 +1,401 @@
+use crate::args::Args;
+use crate::plan::plan_snapshot_path;
+use crate::snapshot::Snapshot;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const OMNI_BALANCES_URL: &str = "https://api.omniexplorer.info/ask.aspx?api=getpropertybalances&prop=3";
+// The block omniexplorer has processed up to, as "last_block"
+const OMNI_STATUS_URL: &str = "https://api.omniexplorer.info/v1/system/status";
+const MAID_PROPERTY_ID: u32 = 3;
+// How many times to fetch balances if a block arrives while fetching
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+const RPC_TIMEOUT_SECS: u64 = 300;
+
+// Somewhere MAID balances can be loaded from.
+// Every source gives the same Snapshot so the rest of distribute doesn't need
+// to know where the balances came from.
+pub trait BalanceSource {
+    fn fetch(&self) -> Result<Snapshot, String>;
+}
+
+// Every source given in the command line options, in the order
+// snapshot file, omni core node, omniexplorer. A run of an approved plan
+// uses the snapshot saved with the plan unless --snapshot is given.
+// The first source is used for the distribution and any others are used to
+// check it. omniexplorer is used if no other source is given, or if it's
+// asked for with --omniexplorer or --omniexplorer-url.
+pub fn balance_sources(args: &Args) -> Result<Vec<Box<dyn BalanceSource>>, String> {
+    let mut sources = Vec::<Box<dyn BalanceSource>>::new();
+    let block_height = match args.value("block-height") {
+        Some(h) => match h.parse::<u64>() {
+            Ok(h) => Some(h),
+            Err(_) => return Err(format!("Invalid --block-height {}", h)),
+        },
+        None => None,
+    };
+    let snapshot_path = match (args.value("snapshot"), args.value("approved-plan")) {
+        (Some(path), _) => Some(PathBuf::from(path)),
+        (None, Some(plan_path)) => Some(plan_snapshot_path(Path::new(plan_path))),
+        (None, None) => None,
+    };
+    if let Some(path) = snapshot_path {
+        sources.push(Box::new(SnapshotFile {
+            path,
+            block_height,
+        }));
+    }
+    if let Some(url) = args.value("omni-rpc") {
+        sources.push(Box::new(OmniCoreRpc {
+            rpc: RpcClient {
+                url: url.clone(),
+                auth: rpc_auth(args, "omni-rpc", "OMNI_RPC_PASSWORD")?,
+            },
+        }));
+    }
+    if sources.is_empty() || args.flag("omniexplorer") || args.value("omniexplorer-url").is_some() {
+        let default_url = OMNI_BALANCES_URL.to_string();
+        sources.push(Box::new(OmniExplorer {
+            url: args.value("omniexplorer-url").unwrap_or(&default_url).clone(),
+        }));
+    }
+    Ok(sources)
+}
+
+// user:password from the <prefix>-cookie file, or the <prefix>-user option
+// and the password environment variable so the password isn't visible in
+// the process list.
+pub fn rpc_auth(args: &Args, prefix: &str, password_env: &str) -> Result<Option<String>, String> {
+    if let Some(cookie_path) = args.value(&format!("{}-cookie", prefix)) {
+        return match fs::read_to_string(cookie_path) {
+            Ok(cookie) => Ok(Some(cookie.trim().to_string())),
+            Err(e) => Err(format!("Unable to read --{}-cookie {}: {}", prefix, cookie_path, e)),
+        };
+    }
+    let user = match args.value(&format!("{}-user", prefix)) {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+    let password = env::var(password_env).unwrap_or_default();
+    Ok(Some(format!("{}:{}", user, password)))
+}
+
+// Snapshots are only returned if their balances can be read, so a bad
+// response or file stops distribute before it's used.
+fn checked(snapshot: Snapshot) -> Result<Snapshot, String> {
+    match snapshot.balances() {
+        Ok(_) => Ok(snapshot),
+        Err(e) => Err(format!("Unable to read balances from {}: {}", snapshot.provenance.source, e)),
+    }
+}
+
+// The omniexplorer.info api
+pub struct OmniExplorer {
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct OmniStatus {
+    last_block: u64,
+}
+
+impl BalanceSource for OmniExplorer {
+    // Balances are fetched between two checks of the block height so the
+    // snapshot is known to be for a single block. omniexplorer balances only
+    // include confirmed transactions so nothing in the mempool is counted.
+    fn fetch(&self) -> Result<Snapshot, String> {
+        // the whole snapshot is cached so the block height stays with the body
+        let cache_key = format!("snapshot {}", self.url);
+        let cached = crate::fetch_from_cache_or(Path::new(crate::CACHE_DIR), &cache_key, || {
+            Ok(serde_json::to_string(&self.fetch_new()?).unwrap())
+        })?;
+        match serde_json::from_str(&cached) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("Invalid cached snapshot for {}: {}", self.url, e)),
+        }
+    }
+}
+
+impl OmniExplorer {
+    fn fetch_new(&self) -> Result<Snapshot, String> {
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let height_before = self.fetch_block_height()?;
+            let body = crate::fetch_from_internet(&self.url);
+            let height_after = self.fetch_block_height()?;
+            if height_before == height_after {
+                // checked before it's cached so an error page isn't kept
+                return checked(Snapshot::new(&self.url, height_after, body, "json"));
+            }
+            println!("Block {} was processed while fetching balances, fetching again", height_after);
+        }
+        Err("Block height kept changing while fetching balances".to_string())
+    }
+
+    fn fetch_block_height(&self) -> Result<u64, String> {
+        let body = crate::fetch_from_internet(OMNI_STATUS_URL);
+        match serde_json::from_str::<OmniStatus>(&body) {
+            Ok(s) => Ok(s.last_block),
+            Err(e) => Err(format!("Unable to read block height from {}: {}\n{}", OMNI_STATUS_URL, e, body)),
+        }
+    }
+}
+
+// A file on disk, either a snapshot saved by distribute which includes the
+// block height, or a json or csv export of balances which needs the block
+// height to be given.
+pub struct SnapshotFile {
+    pub path: PathBuf,
+    pub block_height: Option<u64>,
+}
+
+impl BalanceSource for SnapshotFile {
+    fn fetch(&self) -> Result<Snapshot, String> {
+        let body = match fs::read_to_string(&self.path) {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Unable to open snapshot {}: {}", self.path.display(), e)),
+        };
+        // snapshots saved by distribute
+        if let Ok(snapshot) = serde_json::from_str::<Snapshot>(&body) {
+            if let Err(e) = snapshot.verify() {
+                return Err(format!("Snapshot {} has been modified\n{}", self.path.display(), e));
+            }
+            return checked(snapshot);
+        }
+        // exports of balances
+        let format = match self.path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => "csv",
+            _ => "json",
+        };
+        let block_height = match self.block_height {
+            Some(h) => h,
+            None => return Err(format!("Balances in {} need a block height, use --block-height", self.path.display())),
+        };
+        let source = format!("file {}", self.path.display());
+        checked(Snapshot::new(&source, block_height, body, format))
+    }
+}
+
+// An Omni Core node using json-rpc
+pub struct OmniCoreRpc {
+    pub rpc: RpcClient,
+}
+
+impl BalanceSource for OmniCoreRpc {
+    // Omni Core processes each block as it arrives so the balances are for
+    // the block count, checked before and after fetching balances.
+    fn fetch(&self) -> Result<Snapshot, String> {
+        for _ in 0..SNAPSHOT_ATTEMPTS {
+            let height_before = self.rpc.block_count()?;
+            let balances = self.rpc.call("omni_getallbalancesforid", json!([MAID_PROPERTY_ID]))?;
+            let height_after = self.rpc.block_count()?;
+            if height_before == height_after {
+                let source = format!("omni core {}", self.rpc.url);
+                return checked(Snapshot::new(&source, height_after, balances.to_string(), "json"));
+            }
+            println!("Block {} arrived while fetching balances, fetching again", height_after);
+        }
+        Err("Block height kept changing while fetching balances".to_string())
+    }
+}
+
+// json-rpc to Omni Core or Bitcoin Core
+pub struct RpcClient {
+    pub url: String,
+    // user:password
+    pub auth: Option<String>,
+}
+
+impl RpcClient {
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "distribute",
+            "method": method,
+            "params": params,
+        });
+        let mut http_request = minreq::post(self.url.as_str())
+            .with_header("Content-Type", "application/json")
+            .with_body(request.to_string())
+            .with_timeout(RPC_TIMEOUT_SECS);
+        if let Some(auth) = &self.auth {
+            let auth_header = format!("Basic {}", STANDARD.encode(auth));
+            http_request = http_request.with_header("Authorization", auth_header);
+        }
+        let response = match http_request.send() {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Unable to call {} on {}: {}", method, self.url, e)),
+        };
+        // rpc errors come with a non-200 status but still have a json body
+        let body: Value = match response.as_str().map(serde_json::from_str) {
+            Ok(Ok(b)) => b,
+            _ => return Err(format!("Invalid response to {}, status {}", method, response.status_code)),
+        };
+        if !body["error"].is_null() {
+            return Err(format!("Error from {}: {}", method, body["error"]));
+        }
+        Ok(body["result"].clone())
+    }
+
+    pub fn block_count(&self) -> Result<u64, String> {
+        let count = self.call("getblockcount", json!([]))?;
+        match count.as_u64() {
+            Some(c) => Ok(c),
+            None => Err(format!("Invalid block count from {}: {}", self.url, count)),
+        }
+    }
+}
+
+// Answers json-rpc requests over http on a local port the way Omni Core does,
+// for tests. Each request is a new connection. Returns the url and a thread
+// that ends after the given number of requests, with the methods called.
+fn stub_rpc_node(auth: &str, block_count: u64, balances: Value, requests: usize) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let expected_auth = format!("Basic {}", STANDARD.encode(auth));
+    let thread = std::thread::spawn(move || {
+        let mut methods = Vec::<String>::new();
+        for _ in 0..requests {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            let mut authorized = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    match name.to_lowercase().as_str() {
+                        "content-length" => content_length = value.trim().parse().unwrap(),
+                        "authorization" => authorized = value.trim() == expected_auth,
+                        _ => {}
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let method = request["method"].as_str().unwrap().to_string();
+            let (status, reply) = match method.as_str() {
+                _ if !authorized => (401, json!({ "result": null, "error": { "code": -1, "message": "unauthorized" } })),
+                "getblockcount" => (200, json!({ "result": block_count, "error": null })),
+                "omni_getallbalancesforid" if request["params"] == json!([MAID_PROPERTY_ID]) => (200, json!({ "result": balances, "error": null })),
+                _ => (500, json!({ "result": null, "error": { "code": -32601, "message": "Method not found" } })),
+            };
+            methods.push(method);
+            let reply = reply.to_string();
+            let response = format!("HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reply.len(), reply);
+            let mut stream = stream;
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        methods
+    });
+    (url, thread)
+}
+
+pub fn tests() {
+    let balances = json!([
+        { "address": "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "balance": "10.00000000", "reserved": "0.00000000", "frozen": "0.00000000" },
+        { "address": "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", "balance": "2.50000000", "reserved": "1.00000000", "frozen": "0.00000000" },
+    ]);
+    // balances are fetched from omni core between checks of the block count
+    {
+        let (url, thread) = stub_rpc_node("user:password", 800000, balances.clone(), 3);
+        let source = OmniCoreRpc {
+            rpc: RpcClient {
+                url: url.clone(),
+                auth: Some("user:password".to_string()),
+            },
+        };
+        let snapshot = source.fetch().unwrap();
+        assert!(snapshot.provenance.block_height == 800000, "Omni core snapshot is for block {}", snapshot.provenance.block_height);
+        assert!(snapshot.provenance.source == format!("omni core {}", url), "Omni core snapshot source is {}", snapshot.provenance.source);
+        let parsed = snapshot.balances().unwrap();
+        assert!(parsed.len() == 2 && parsed[1].balance == "2.50000000", "Omni core balances are {:?}", parsed);
+        let methods = thread.join().unwrap();
+        assert!(methods == ["getblockcount", "omni_getallbalancesforid", "getblockcount"], "Omni core was asked for {:?}", methods);
+    }
+    // rpc errors are returned rather than panicking
+    {
+        let (url, thread) = stub_rpc_node("user:password", 800000, balances.clone(), 2);
+        let rpc = RpcClient {
+            url: url.clone(),
+            auth: Some("user:password".to_string()),
+        };
+        let result = rpc.call("omni_getallbalancesforid", json!([1]));
+        assert!(result.as_ref().is_err_and(|e| e.contains("Method not found")), "Rpc error gave {:?}", result);
+        let wrong_auth = RpcClient {
+            url,
+            auth: Some("user:wrong".to_string()),
+        };
+        assert!(wrong_auth.block_count().is_err(), "Unauthorized rpc did not return error");
+        thread.join().unwrap();
+    }
+    // loading the same export twice gives the same provenance
+    {
+        let path = env::temp_dir().join(format!("distribute_sources_test_{}.json", std::process::id()));
+        fs::write(&path, balances.to_string()).unwrap();
+        let source = SnapshotFile {
+            path: path.clone(),
+            block_height: Some(800000),
+        };
+        let first = source.fetch().unwrap();
+        let mut second = source.fetch().unwrap();
+        second.provenance.timestamp = first.provenance.timestamp + 60;
+        assert!(first.provenance == second.provenance, "Same export gave {:?} and {:?}", first.provenance, second.provenance);
+        let other_block = SnapshotFile {
+            path: path.clone(),
+            block_height: Some(800001),
+        };
+        assert!(other_block.fetch().unwrap().provenance != first.provenance, "Export at a different block has the same provenance");
+        let _ = fs::remove_file(&path);
+    }
+    // malformed exports and missing cookie files are errors
+    {
+        let path = env::temp_dir().join(format!("distribute_sources_test_{}.csv", std::process::id()));
+        fs::write(&path, "address,balance\n1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH\n").unwrap();
+        let source = SnapshotFile {
+            path: path.clone(),
+            block_height: Some(800000),
+        };
+        let result = source.fetch();
+        assert!(result.as_ref().is_err_and(|e| e.contains("row 1")), "Malformed csv gave {:?}", result.map(|s| s.provenance));
+        fs::write(&path, "<html>Service Unavailable</html>").unwrap();
+        let source = SnapshotFile {
+            path: path.with_extension("json"),
+            block_height: Some(800000),
+        };
+        fs::rename(&path, &source.path).unwrap();
+        assert!(source.fetch().is_err(), "Error page was read as balances");
+        let _ = fs::remove_file(&source.path);
+        let args = Args::parse(["distribute", "plan", "--omni-rpc", "http://127.0.0.1:8332", "--omni-rpc-cookie", "/nonexistent/.cookie"].iter().map(|a| a.to_string()).collect());
+        let result = rpc_auth(&args, "omni-rpc", "OMNI_RPC_PASSWORD");
+        assert!(result.as_ref().is_err_and(|e| e.contains("/nonexistent/.cookie")), "Missing cookie gave {:?}", result);
+    }
+    // an invalid block height is an error
+    {
+        let args = Args::parse(["distribute", "plan", "--snapshot", "s.json", "--block-height", "80000o"].iter().map(|a| a.to_string()).collect());
+        let result = balance_sources(&args);
+        assert!(result.as_ref().is_err_and(|e| e.contains("80000o")), "Invalid block height gave {} sources", result.map(|s| s.len()).unwrap_or(0));
+    }
+    // a node that isn't running is an error
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let source = OmniCoreRpc {
+            rpc: RpcClient {
+                url,
+                auth: None,
+            },
+        };
+        assert!(source.fetch().is_err(), "Missing omni core node did not return error");
+    }
+}
@@ -0,0 +1,174 @@
+use bitcoin::{Address, Network, PublicKey};
+use std::fmt;
+use std::str::FromStr;
+
+// Why a public key can't be used for an address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationError {
+    InvalidPublicKey,
+    InvalidAddress,
+    // a valid address for another network, eg testnet
+    InvalidNetwork,
+    // the key and address are valid but the key isn't for the address
+    KeyMismatch,
+}
+
+impl ValidationError {
+    pub const ALL: [ValidationError; 4] = [
+        ValidationError::InvalidPublicKey,
+        ValidationError::InvalidAddress,
+        ValidationError::InvalidNetwork,
+        ValidationError::KeyMismatch,
+    ];
+
+    // The message shown to whoever submitted the pair
+    pub fn message(&self) -> &'static str {
+        match self {
+            ValidationError::InvalidPublicKey => "Invalid public key",
+            ValidationError::InvalidAddress => "Invalid address",
+            ValidationError::InvalidNetwork => "Invalid network",
+            ValidationError::KeyMismatch => "Public key does not match address",
+        }
+    }
+
+    // A name for the error that won't change, eg for api error codes and
+    // counting errors
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ValidationError::InvalidPublicKey => "invalid_public_key",
+            ValidationError::InvalidAddress => "invalid_address",
+            ValidationError::InvalidNetwork => "invalid_network",
+            ValidationError::KeyMismatch => "key_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+// Every address the public key can receive to.
+// Segwit only allows compressed keys so an uncompressed key is only p2pkh.
+pub fn addresses_for_public_key(pk: &PublicKey) -> Vec<Address> {
+    let mut addresses = vec![Address::p2pkh(pk, Network::Bitcoin)];
+    if let Ok(p2shwpkh_addr) = Address::p2shwpkh(pk, Network::Bitcoin) {
+        addresses.push(p2shwpkh_addr);
+    }
+    if let Ok(p2wpkh_addr) = Address::p2wpkh(pk, Network::Bitcoin) {
+        addresses.push(p2wpkh_addr);
+    }
+    addresses
+}
+
+// The form an address is saved in, as a record key or filename. bech32
+// addresses can be upper or lower case so they're lowercased, otherwise the
+// same address could be saved twice. Addresses that can't be read are only
+// trimmed and are rejected when they're validated.
+pub fn canonical_address(address: &str) -> String {
+    let address = address.trim();
+    match Address::from_str(address) {
+        Ok(a) => a.assume_checked().to_string(),
+        Err(_) => address.to_string(),
+    }
+}
+
+// Checks the public key is valid and is the key for the bitcoin address.
+// Supports p2pkh, p2sh-p2wpkh and p2wpkh (bc1q) addresses.
+pub fn validate_bitcoin_pair(address: &str, pk_hex: &str) -> Result<(), ValidationError> {
+    // bitcoin public key is valid
+    let pk = match PublicKey::from_str(pk_hex) {
+        Ok(pk) => pk,
+        Err(_) => return Err(ValidationError::InvalidPublicKey),
+    };
+    // bitcoin address is valid
+    let addr = match Address::from_str(address) {
+        Ok(a) => a,
+        Err(_) => return Err(ValidationError::InvalidAddress),
+    };
+    let btc_addr = match addr.require_network(Network::Bitcoin) {
+        Ok(a) => a,
+        Err(_) => return Err(ValidationError::InvalidNetwork),
+    };
+    // bitcoin public key matches bitcoin address
+    if addresses_for_public_key(&pk).contains(&btc_addr) {
+        return Ok(());
+    }
+    Err(ValidationError::KeyMismatch)
+}
+
+pub fn tests() {
+    // secret key 1
+    let compressed = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let uncompressed = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+    // every supported address type
+    let valid = [
+        // p2pkh compressed
+        ("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", compressed),
+        // p2pkh uncompressed
+        ("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", uncompressed),
+        // p2sh-p2wpkh
+        ("3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN", compressed),
+        // p2wpkh
+        ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", compressed),
+        // p2wpkh uppercase
+        ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", compressed),
+        ("1CoT3ACy3L8MUSRcRbi9FuZ8Yckz3Ghpwz", "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc"),
+        ("39Q6Y89u1wMYacDJw63UNiYgj3wfJtZbRj", "03608934ee3cd78469528f55bab4f1db60f3fbdd793067503dfef6d7903dbf61e9"),
+    ];
+    for (address, pk_hex) in valid {
+        let r = validate_bitcoin_pair(address, pk_hex);
+        assert!(r.is_ok(), "Valid bitcoin pair {} {} threw error: {:?}", address, pk_hex, r);
+    }
+    // segwit addresses can't use uncompressed keys
+    let invalid = [
+        // p2wpkh of the uncompressed key hash
+        ("bc1qjxeyh7049zzn99s2c6r6hvp4zfa362997dpu0h", uncompressed),
+        // p2wpkh for the compressed key
+        ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", uncompressed),
+        // p2pkh for the other form of the key
+        ("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", compressed),
+    ];
+    for (address, pk_hex) in invalid {
+        let r = validate_bitcoin_pair(address, pk_hex);
+        assert!(r == Err(ValidationError::KeyMismatch), "Invalid bitcoin pair {} {} gave {:?}", address, pk_hex, r);
+    }
+    // bech32 addresses are lowercase, base58 addresses are unchanged
+    {
+        let cases = [
+            ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            (" bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4\n", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            ("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"),
+            ("3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN", "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN"),
+            ("not an address ", "not an address"),
+        ];
+        for (address, expected) in cases {
+            let canonical = canonical_address(address);
+            assert!(canonical == expected, "Canonical address for {:?} is {}", address, canonical);
+        }
+    }
+    // testnet p2wpkh
+    {
+        let r = validate_bitcoin_pair("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", compressed);
+        assert!(r == Err(ValidationError::InvalidNetwork), "Testnet p2wpkh address gave {:?}", r);
+    }
+    // mismatched pair
+    {
+        let r = validate_bitcoin_pair("1Kr6QSydW9bFQG1mXiPNNu6WpJGmUa9i1g", "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc");
+        assert!(r == Err(ValidationError::KeyMismatch), "Mismatched bitcoin pair gave {:?}", r);
+    }
+    // testnet address
+    {
+        let r = validate_bitcoin_pair("msKQLDHwrMZcFYuE9AgX5pmTQcMgsXx8Bx", "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc");
+        assert!(r == Err(ValidationError::InvalidNetwork), "Testnet address gave {:?}", r);
+    }
+    // invalid key and address
+    {
+        let r = validate_bitcoin_pair("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "02");
+        assert!(r == Err(ValidationError::InvalidPublicKey), "Invalid public key gave {:?}", r);
+        let r = validate_bitcoin_pair("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMX", compressed);
+        assert!(r == Err(ValidationError::InvalidAddress), "Invalid address gave {:?}", r);
+        assert!(r.unwrap_err().to_string() == "Invalid address", "Error message is {}", r.unwrap_err());
+    }
+}
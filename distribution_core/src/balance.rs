@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+// A MAID holder, in the omniexplorer json format.
+// public_key is only set once a key has been found for the address.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct OMaidBalance {
+    pub address: String,
+    pub balance: String,
+    pub reserved: String,
+    pub public_key: Option<String>,
+}
+
+impl OMaidBalance {
+    // Whether the address will receive a distribution, the same rule as the
+    // distribution plan which skips zero and invalid balances.
+    pub fn is_eligible(&self) -> bool {
+        matches!(self.balance.parse::<u32>(), Ok(n) if n > 0)
+    }
+}
+
+// The parts of a snapshot saved by distribute needed to read the balances
+#[derive(Deserialize)]
+struct SavedSnapshot {
+    #[serde(default)]
+    format: Option<String>,
+    body: String,
+}
+
+// Balances from a json list of OMaidBalance, or from a json snapshot saved by
+// distribute which has the list as its body.
+pub fn parse_balances(json: &str) -> Result<Vec<OMaidBalance>, String> {
+    if let Ok(balances) = serde_json::from_str::<Vec<OMaidBalance>>(json) {
+        return Ok(balances);
+    }
+    let snapshot = match serde_json::from_str::<SavedSnapshot>(json) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Not a list of balances or a snapshot: {}", e)),
+    };
+    if snapshot.format.as_deref().unwrap_or("json") != "json" {
+        return Err("Only json snapshots can be read".to_string());
+    }
+    match serde_json::from_str::<Vec<OMaidBalance>>(&snapshot.body) {
+        Ok(b) => Ok(b),
+        Err(e) => Err(format!("Invalid balances in snapshot body: {}", e)),
+    }
+}
+
+pub fn tests() {
+    let list = r#"[{"address":"1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH","balance":"10","reserved":"0"},{"address":"1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm","balance":"0","reserved":"5"}]"#;
+    // omniexplorer list
+    {
+        let balances = parse_balances(list).unwrap();
+        assert!(balances.len() == 2, "Parsed {} balances", balances.len());
+        assert!(balances[0].public_key.is_none(), "Balance without key has key {:?}", balances[0].public_key);
+        assert!(balances[0].is_eligible(), "Balance of 10 is not eligible");
+        assert!(!balances[1].is_eligible(), "Zero balance is eligible");
+    }
+    // snapshot saved by distribute
+    {
+        let snapshot = serde_json::json!({
+            "provenance": {"source": "test", "block_height": 800000, "timestamp": 0, "sha256": ""},
+            "format": "json",
+            "body": list,
+        });
+        let balances = parse_balances(&snapshot.to_string());
+        assert!(balances.map(|b| b.len()) == Ok(2), "Snapshot balances not parsed");
+        let csv = serde_json::json!({"format": "csv", "body": "address,balance\n"});
+        assert!(parse_balances(&csv.to_string()).is_err(), "Csv snapshot did not return error");
+    }
+    // invalid balances
+    {
+        let b = OMaidBalance {
+            address: "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH".to_string(),
+            balance: "1.5".to_string(),
+            reserved: "0".to_string(),
+            public_key: None,
+        };
+        assert!(!b.is_eligible(), "Invalid balance is eligible");
+        assert!(parse_balances("{}").is_err(), "Junk did not return error");
+    }
+}
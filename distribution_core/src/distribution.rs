@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+// The format of distributions made by this version of distribute.
+// Version 0 is the original format with only transfer and secret_key.
+pub const DISTRIBUTION_VERSION: u32 = 1;
+
+// What each MAID holder receives, encrypted to the public key of their
+// bitcoin address.
+//
+// Version 0 was encoded as a msgpack array of transfer and secret_key.
+// Later versions are encoded as a msgpack map so fields can be added without
+// breaking older clients. Decoding a version 0 array fills the remaining
+// fields with their defaults, so the field order here must not change.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct MaidDistribution {
+    #[serde(with = "serde_bytes")]
+    pub transfer: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub secret_key: Vec<u8>,
+    #[serde(default)]
+    pub version: u32,
+    // The fields below are empty in version 0
+    #[serde(default)]
+    pub maid_address: String,
+    // whole MAID
+    #[serde(default)]
+    pub amount: u64,
+    #[serde(default)]
+    pub snapshot_block_height: u64,
+    // the safe network the transfer is for
+    #[serde(default)]
+    pub network_id: String,
+}
+
+impl MaidDistribution {
+    pub fn new(transfer: Vec<u8>, secret_key: Vec<u8>, maid_address: &str, amount: u64, snapshot_block_height: u64, network_id: &str) -> MaidDistribution {
+        MaidDistribution {
+            transfer,
+            secret_key,
+            version: DISTRIBUTION_VERSION,
+            maid_address: maid_address.to_string(),
+            amount,
+            snapshot_block_height,
+            network_id: network_id.to_string(),
+        }
+    }
+
+    // msgpack bytes, as a map of field names to values
+    pub fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).unwrap()
+    }
+
+    // Decodes any version up to DISTRIBUTION_VERSION
+    pub fn from_bytes(bytes: &[u8]) -> Result<MaidDistribution, String> {
+        let md: MaidDistribution = match rmp_serde::from_slice(bytes) {
+            Ok(md) => md,
+            Err(e) => return Err(format!("Invalid distribution: {}", e)),
+        };
+        if md.version > DISTRIBUTION_VERSION {
+            return Err(format!("Distribution version {} is newer than this software supports, please upgrade", md.version));
+        }
+        Ok(md)
+    }
+
+    // ECIES encryption to the bitcoin public key, compressed or uncompressed
+    pub fn encrypt(&self, pk_bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match ecies::encrypt(pk_bytes, &self.to_bytes()) {
+            Ok(e) => Ok(e),
+            Err(e) => Err(format!("Unable to encrypt distribution: {:?}", e)),
+        }
+    }
+
+    // Decrypts with the bitcoin secret key
+    pub fn decrypt(encrypted: &[u8], sk_bytes: &[u8]) -> Result<MaidDistribution, String> {
+        let md_bytes = match ecies::decrypt(sk_bytes, encrypted) {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Unable to decrypt distribution: {:?}", e)),
+        };
+        MaidDistribution::from_bytes(&md_bytes)
+    }
+}
+
+pub fn tests() {
+    // bip39 phrase wedding pig fiscal, m/44'/0'/0'/0/0
+    let sk = bitcoin::PrivateKey::from_wif("KyNdvxT1Ead7AD9thdvg8399fVxC1Tdf9FvPc2dqmmnHstcTUH5y").unwrap();
+    let pk = sk.public_key(&bitcoin::secp256k1::Secp256k1::new());
+    let md = MaidDistribution::new(vec![1, 2, 3, 4], vec![5; 32], "1LyVLuxCbgLgYCZ6Sk6BrPJqAhixuyJpP7", 1, 800000, "mainnet");
+    // msgpack round trip
+    {
+        let decoded = MaidDistribution::from_bytes(&md.to_bytes());
+        assert!(decoded.as_ref() == Ok(&md), "Distribution changed after encoding: {:?}", decoded);
+    }
+    // encryption round trip
+    {
+        let encrypted = md.encrypt(&pk.to_bytes()).unwrap();
+        let decrypted = MaidDistribution::decrypt(&encrypted, &sk.to_bytes());
+        assert!(decrypted.as_ref() == Ok(&md), "Distribution changed after encryption: {:?}", decrypted);
+    }
+    // uncompressed public key
+    {
+        let uncompressed = bitcoin::PublicKey::new_uncompressed(pk.inner);
+        let encrypted = md.encrypt(&uncompressed.to_bytes()).unwrap();
+        let decrypted = MaidDistribution::decrypt(&encrypted, &sk.to_bytes());
+        assert!(decrypted.as_ref() == Ok(&md), "Distribution for uncompressed key changed: {:?}", decrypted);
+    }
+    // wrong secret key
+    {
+        let encrypted = md.encrypt(&pk.to_bytes()).unwrap();
+        let decrypted = MaidDistribution::decrypt(&encrypted, &[7; 32]);
+        assert!(decrypted.is_err(), "Distribution decrypted with the wrong key");
+    }
+    // version 0 distributions still decode
+    {
+        #[derive(Serialize)]
+        struct MaidDistributionV0 {
+            #[serde(with = "serde_bytes")]
+            transfer: Vec<u8>,
+            #[serde(with = "serde_bytes")]
+            secret_key: Vec<u8>,
+        }
+        let v0 = MaidDistributionV0 {
+            transfer: vec![1, 2, 3, 4],
+            secret_key: vec![5; 32],
+        };
+        let decoded = MaidDistribution::from_bytes(&rmp_serde::to_vec(&v0).unwrap()).unwrap();
+        assert!(decoded.version == 0, "Version 0 distribution decoded as version {}", decoded.version);
+        assert!(decoded.transfer == v0.transfer && decoded.secret_key == v0.secret_key, "Version 0 distribution changed: {:?}", decoded);
+        assert!(decoded.maid_address.is_empty() && decoded.amount == 0, "Version 0 distribution has extra fields: {:?}", decoded);
+    }
+    // versions from the future are rejected
+    {
+        let mut future = MaidDistribution::new(vec![1], vec![5; 32], "1LyVLuxCbgLgYCZ6Sk6BrPJqAhixuyJpP7", 1, 800000, "mainnet");
+        future.version = DISTRIBUTION_VERSION + 1;
+        let decoded = MaidDistribution::from_bytes(&future.to_bytes());
+        assert!(decoded.is_err(), "Distribution from a newer version decoded");
+    }
+    // junk
+    {
+        let decoded = MaidDistribution::from_bytes(&[0xc1, 0x00]);
+        assert!(decoded.is_err(), "Junk decoded as a distribution");
+    }
+}
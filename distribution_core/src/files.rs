@@ -0,0 +1,97 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// The temporary file is in the same directory so the rename can't cross
+// filesystems, and starts with a dot so it's never read as an address.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+// Writes the file so it has either the old or the new content, never part of
+// the new content, even if the process crashes or two writes happen at once.
+// Callers writing the same path concurrently must lock around this since they
+// share the temporary file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_file(path, contents, false)
+}
+
+// write_atomic for secrets. The temporary file is only readable by its owner
+// from when it's created, before anything is written to it.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_file(path, contents, true)
+}
+
+fn write_file(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    let temp = temp_path(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        // a temporary file left by a crash keeps its permissions, so it's
+        // removed and a new one created
+        let _ = fs::remove_file(&temp);
+        options.create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+    }
+    let mut file = options.open(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    // the rename is only durable once the directory is synced, which isn't
+    // possible on every platform so failure is ignored
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    if let Ok(d) = fs::File::open(dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+pub fn tests() {
+    let dir = std::env::temp_dir().join(format!("distribution_core_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH");
+    // new file
+    {
+        write_atomic(&path, b"first").unwrap();
+        let content = fs::read(&path).unwrap();
+        assert!(content == b"first", "Atomic write saved {:?}", content);
+    }
+    // existing file is replaced, not appended to or left partly overwritten
+    {
+        write_atomic(&path, b"2nd").unwrap();
+        let content = fs::read(&path).unwrap();
+        assert!(content == b"2nd", "Atomic overwrite saved {:?}", content);
+    }
+    // no temporary file is left behind
+    {
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert!(files == 1, "Atomic write left {} files", files);
+        assert!(!temp_path(&path).exists(), "Atomic write left the temporary file");
+    }
+    // private files are only readable by the owner, even if an earlier
+    // temporary file was left readable
+    {
+        let private = dir.join("secret");
+        fs::write(temp_path(&private), b"left by a crash").unwrap();
+        write_private(&private, b"secret").unwrap();
+        let content = fs::read(&private).unwrap();
+        assert!(content == b"secret", "Private write saved {:?}", content);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&private).unwrap().permissions().mode() & 0o777;
+            assert!(mode == 0o600, "Private file has mode {:o}", mode);
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
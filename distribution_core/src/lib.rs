@@ -0,0 +1,23 @@
+// Everything that distribute, public_key_server and pubkey_submit need to
+// agree on, so the format and checks can't drift apart between them.
+
+mod address;
+mod balance;
+mod distribution;
+mod files;
+mod receipt;
+
+pub use address::{addresses_for_public_key, canonical_address, validate_bitcoin_pair, ValidationError};
+pub use balance::{parse_balances, OMaidBalance};
+pub use distribution::{MaidDistribution, DISTRIBUTION_VERSION};
+pub use files::{write_atomic, write_private};
+pub use receipt::{Receipt, RECEIPT_VERSION};
+
+// There's no `cargo test`, each binary runs these when it starts.
+pub fn tests() {
+    address::tests();
+    balance::tests();
+    distribution::tests();
+    files::tests();
+    receipt::tests();
+}
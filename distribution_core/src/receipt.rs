@@ -0,0 +1,117 @@
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const RECEIPT_VERSION: u8 = 1;
+
+// Proof from public_key_server that a public key was submitted for an address
+// at a time. Signed by the server so it can be checked offline against the
+// published server key.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Receipt {
+    pub version: u8,
+    pub address: String,
+    pub pkhex: String,
+    // unix time in seconds
+    pub timestamp: u64,
+    // the public key of the server that signed it, hex
+    pub server_key: String,
+    // compact ecdsa signature, hex
+    pub signature: String,
+}
+
+// The hash that is signed, covering everything in the receipt except the
+// signature. The server key is in the receipt for convenience, receipts are
+// checked against the published key.
+fn receipt_hash(version: u8, address: &str, pkhex: &str, timestamp: u64) -> Message {
+    let text = format!("public_key_server receipt v{}\n{}\n{}\n{}", version, address, pkhex, timestamp);
+    let hash = sha256::Hash::hash(text.as_bytes());
+    Message::from_digest(hash.to_byte_array())
+}
+
+impl Receipt {
+    pub fn sign(address: &str, pkhex: &str, timestamp: u64, server_sk: &SecretKey) -> Receipt {
+        let secp = Secp256k1::new();
+        let msg = receipt_hash(RECEIPT_VERSION, address, pkhex, timestamp);
+        let sig = secp.sign_ecdsa(&msg, server_sk);
+        Receipt {
+            version: RECEIPT_VERSION,
+            address: address.to_string(),
+            pkhex: pkhex.to_string(),
+            timestamp,
+            server_key: server_sk.public_key(&secp).to_string(),
+            signature: hex::encode(sig.serialize_compact()),
+        }
+    }
+
+    // Checks the receipt was signed by the server with the public key
+    // server_key_hex, which should come from the published key rather than
+    // the receipt.
+    pub fn verify(&self, server_key_hex: &str) -> Result<(), &'static str> {
+        if self.version != RECEIPT_VERSION {
+            return Err("Unknown receipt version");
+        }
+        let server_key = match PublicKey::from_str(server_key_hex.trim()) {
+            Ok(pk) => pk,
+            Err(_) => return Err("Invalid server key"),
+        };
+        if self.server_key.to_lowercase() != server_key.to_string() {
+            return Err("Receipt is from a different server key");
+        }
+        let sig_bytes = match hex::decode(&self.signature) {
+            Ok(b) => b,
+            Err(_) => return Err("Invalid signature"),
+        };
+        let sig = match Signature::from_compact(&sig_bytes) {
+            Ok(s) => s,
+            Err(_) => return Err("Invalid signature"),
+        };
+        let msg = receipt_hash(self.version, &self.address, &self.pkhex, self.timestamp);
+        let secp = Secp256k1::verification_only();
+        match secp.verify_ecdsa(&msg, &sig, &server_key) {
+            Ok(()) => Ok(()),
+            Err(_) => Err("Receipt signature is not valid"),
+        }
+    }
+}
+
+pub fn tests() {
+    let sk = SecretKey::from_slice(&[7u8; 32]).unwrap();
+    let server_key = sk.public_key(&Secp256k1::new()).to_string();
+    let address = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    let pkhex = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let receipt = Receipt::sign(address, pkhex, 1700000000, &sk);
+    // valid receipt
+    {
+        let r = receipt.verify(&server_key);
+        assert!(r.is_ok(), "Valid receipt did not verify: {:?}", r);
+        // receipts survive being saved as json
+        let json = serde_json::to_string(&receipt).unwrap();
+        let loaded: Receipt = serde_json::from_str(&json).unwrap();
+        assert!(loaded.verify(&server_key).is_ok(), "Receipt from json did not verify");
+    }
+    // changed fields don't verify
+    {
+        let mut r = receipt.clone();
+        r.timestamp -= 1;
+        assert!(r.verify(&server_key).is_err(), "Receipt with changed time verified");
+        let mut r = receipt.clone();
+        r.address = "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm".to_string();
+        assert!(r.verify(&server_key).is_err(), "Receipt with changed address verified");
+        let mut r = receipt.clone();
+        r.pkhex = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8".to_string();
+        assert!(r.verify(&server_key).is_err(), "Receipt with changed key verified");
+    }
+    // other server keys don't verify
+    {
+        let other_sk = SecretKey::from_slice(&[8u8; 32]).unwrap();
+        let other_key = other_sk.public_key(&Secp256k1::new()).to_string();
+        assert!(receipt.verify(&other_key).is_err(), "Receipt verified with other key");
+        // signed by another key but claiming to be the server
+        let mut forged = Receipt::sign(address, pkhex, 1700000000, &other_sk);
+        forged.server_key = server_key.clone();
+        assert!(forged.verify(&server_key).is_err(), "Forged receipt verified");
+        assert!(receipt.verify("02").is_err(), "Invalid server key did not return error");
+    }
+}
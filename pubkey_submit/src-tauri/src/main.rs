@@ -1,33 +1,57 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use distribution_core::{MaidDistribution, Receipt};
 use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Deserialize, Serialize)]
-struct MaidDistribution {
-    #[serde(with = "serde_bytes")]
-    transfer: Vec<u8>,
-    #[serde(with = "serde_bytes")]
-    secret_key: Vec<u8>,
-}
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+// Older distributions have version 0 and no address, amount, snapshot or
+// network, these are returned as empty strings.
 #[tauri::command]
-fn decrypt_distribution(sk_wif: &str, distribution_hex: &str) -> String {
-    let sk = bitcoin::PrivateKey::from_wif(sk_wif).unwrap();
-    let md_bytes = hex::decode(distribution_hex).unwrap();
-    let decrypted_md_bytes = ecies::decrypt(&sk.to_bytes(), &md_bytes).unwrap();
-    let md: MaidDistribution = rmp_serde::from_slice(&decrypted_md_bytes).unwrap();
+fn decrypt_distribution(sk_wif: &str, distribution_hex: &str) -> Result<String, String> {
+    let sk = match bitcoin::PrivateKey::from_wif(sk_wif.trim()) {
+        Ok(sk) => sk,
+        Err(_) => return Err("Invalid secret key, it must be in WIF format".to_string()),
+    };
+    let md_bytes = match hex::decode(distribution_hex.trim()) {
+        Ok(b) => b,
+        Err(_) => return Err("Invalid distribution, it must be hex".to_string()),
+    };
+    let md = MaidDistribution::decrypt(&md_bytes, &sk.to_bytes())?;
     let mut md_map = HashMap::new();
     md_map.insert("transfer", hex::encode(&md.transfer));
     md_map.insert("secret_key", hex::encode(&md.secret_key));
-    serde_json::to_string(&md_map).unwrap()
+    md_map.insert("version", md.version.to_string());
+    md_map.insert("maid_address", md.maid_address.clone());
+    md_map.insert("network_id", md.network_id.clone());
+    if md.version > 0 {
+        md_map.insert("amount", md.amount.to_string());
+        md_map.insert("snapshot_block_height", md.snapshot_block_height.to_string());
+    } else {
+        md_map.insert("amount", "".to_string());
+        md_map.insert("snapshot_block_height", "".to_string());
+    }
+    match serde_json::to_string(&md_map) {
+        Ok(j) => Ok(j),
+        Err(e) => Err(format!("Unable to show distribution: {}", e)),
+    }
+}
+
+// Checks a receipt from public_key_server was signed by the server key, so
+// it can be shown as proof the public key was submitted.
+#[tauri::command]
+fn verify_receipt(receipt_json: &str, server_key: &str) -> Result<(), String> {
+    let receipt: Receipt = match serde_json::from_str(receipt_json) {
+        Ok(r) => r,
+        Err(_) => return Err("Invalid receipt".to_string()),
+    };
+    receipt.verify(server_key)?;
+    Ok(())
 }
 
 fn main() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![decrypt_distribution])
+        .invoke_handler(tauri::generate_handler![decrypt_distribution, verify_receipt])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
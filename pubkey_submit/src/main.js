@@ -1,8 +1,26 @@
 const invoke = window.__TAURI__.tauri.invoke;
 const fetch = window.__TAURI__.http.fetch;
 const ResponseType = window.__TAURI__.http.ResponseType;
-
-const submitUrl = "http://127.0.0.1:8080/submit";
+const Body = window.__TAURI__.http.Body;
+
+const keysUrl = "http://127.0.0.1:8080/api/v1/keys";
+
+// receipts are kept by address in local storage under this name
+const receiptsStorageKey = "receipts";
+
+// messages for the error codes from the keys api
+const apiErrors = {
+    invalid_request: "Invalid request",
+    invalid_public_key: "Invalid public key",
+    invalid_address: "Invalid address",
+    invalid_network: "Address is not for bitcoin mainnet",
+    key_mismatch: "Public key is not for this address, check the key type",
+    not_eligible: "This address has no MAID in the snapshot",
+    round_closed: "Submissions are closed until the next round opens",
+    not_found: "Public key has not been submitted for this address",
+    storage_error: "Server error saving the public key, try again later",
+    rate_limited: "Too many requests, try again in a minute",
+};
 
 let distributionList = {};
 
@@ -13,10 +31,16 @@ DOM.secretKeyType = document.querySelector(".secret-key-type");
 DOM.publicKey = document.querySelector(".public-key");
 DOM.compressed = document.querySelector(".compressed");
 DOM.uncompressed = document.querySelector(".uncompressed");
+DOM.p2shP2wpkh = document.querySelector(".p2sh-p2wpkh");
 DOM.p2wpkh = document.querySelector(".p2wpkh");
 DOM.address = document.querySelector(".address");
 DOM.submit = document.querySelector(".submit");
+DOM.check = document.querySelector(".check");
 DOM.submitFeedback = document.querySelector(".feedback");
+DOM.receipt = document.querySelector(".receipt");
+DOM.serverKey = document.querySelector(".server-key");
+DOM.verifyReceipt = document.querySelector(".verify-receipt");
+DOM.receiptResult = document.querySelector(".receipt-result");
 DOM.clear = document.querySelectorAll(".clear-secrets");
 
 DOM.listFile = document.querySelector(".list-file");
@@ -25,12 +49,20 @@ DOM.fileResult = document.querySelector(".file-result");
 DOM.maidSecret = document.querySelector(".maid-secret");
 DOM.distributionResult = document.querySelector(".distribution-result");
 
+DOM.distributionDetails = document.querySelector(".distribution-details");
 DOM.receiveSk = document.querySelector(".receive-sk");
 DOM.receiveTransfer = document.querySelector(".receive-transfer");
 
 const ONLINE_STR = "This computer is currently online and connected to the internet";
 const OFFLINE_STR = "This computer is currently offline";
 
+async function verifyReceipt(receiptJson, serverKey) {
+  return await invoke("verify_receipt", {
+      receiptJson: receiptJson,
+      serverKey: serverKey,
+  });
+}
+
 async function decryptDistribution(skWif, distributionHex) {
   return await invoke("decrypt_distribution", {
       skWif: skWif,
@@ -70,6 +102,11 @@ function showUncompressed() {
     DOM.uncompressed.checked = true;
 }
 
+function showP2shP2wpkh() {
+    DOM.secretKeyType.textContent = "p2sh-p2wpkh";
+    DOM.p2shP2wpkh.checked = true;
+}
+
 function showP2wpkh() {
     DOM.secretKeyType.textContent = "p2wpkh";
     DOM.p2wpkh.checked = true;
@@ -123,7 +160,8 @@ function publicKeyChanged() {
 }
 
 function pkTypeChanged() {
-    let compressed = DOM.compressed.checked || DOM.p2wpkh.checked;
+    // segwit only uses compressed keys
+    let compressed = DOM.compressed.checked || DOM.p2shP2wpkh.checked || DOM.p2wpkh.checked;
     // show status in UI for sk and radios
     if (DOM.compressed.checked) {
         showCompressed();
@@ -131,6 +169,9 @@ function pkTypeChanged() {
     else if (DOM.uncompressed.checked) {
         showUncompressed();
     }
+    else if (DOM.p2shP2wpkh.checked) {
+        showP2shP2wpkh();
+    }
     else if (DOM.p2wpkh.checked) {
         showP2wpkh();
     }
@@ -162,20 +203,20 @@ function submit() {
         return;
     }
     DOM.submitFeedback.textContent = "Submitting...";
-    let params = "?address=" + address + "&pkhex=" + pkhex;
-    let url = submitUrl + params;
 
-    fetch(url, {
-      method: "GET",
+    fetch(keysUrl, {
+      method: "POST",
       timeout: 30, //seconds
-      responseType: ResponseType.Text,
+      body: Body.json({ address: address, pkhex: pkhex }),
+      responseType: ResponseType.JSON,
     })
       .then((resp) => {
         if (resp.ok) {
             DOM.submitFeedback.textContent = "Public key submitted";
+            saveReceipt(resp.data.receipt);
         }
         else {
-            let errMsg = "Error submitting: " + resp.data;
+            let errMsg = "Error submitting: " + apiErrorMessage(resp);
             DOM.submitFeedback.textContent = errMsg;
         }
       })
@@ -185,6 +226,103 @@ function submit() {
       });
 }
 
+// Shows if the public key for this address is already on the server
+function checkSubmitted() {
+    let address = DOM.address.textContent.trim();
+    let pkhex = DOM.publicKey.value.trim().toLowerCase();
+    if (address.length == 0) {
+        DOM.submitFeedback.textContent = "Empty address";
+        return;
+    }
+    DOM.submitFeedback.textContent = "Checking...";
+    fetch(keysUrl + "/" + encodeURIComponent(address), {
+      method: "GET",
+      timeout: 30, //seconds
+      responseType: ResponseType.JSON,
+    })
+      .then((resp) => {
+        if (resp.ok && resp.data.pkhex == pkhex) {
+            let submitted = new Date(resp.data.last_seen * 1000);
+            DOM.submitFeedback.textContent = "Public key is on file, last submitted " + submitted.toLocaleString();
+            showSavedReceipt(address);
+        }
+        else if (resp.ok) {
+            DOM.submitFeedback.textContent = "A different public key is on file for this address";
+        }
+        else {
+            DOM.submitFeedback.textContent = apiErrorMessage(resp);
+        }
+      })
+      .catch((e) => {
+          let errMsg = "Error: " + e;
+          DOM.submitFeedback.textContent = errMsg;
+      });
+}
+
+function loadReceipts() {
+    try {
+        return JSON.parse(window.localStorage.getItem(receiptsStorageKey)) || {};
+    }
+    catch (e) {
+        return {};
+    }
+}
+
+// Keeps the receipt so it can be shown later as proof of the submission
+function saveReceipt(receipt) {
+    if (!receipt) {
+        return;
+    }
+    let receipts = loadReceipts();
+    receipts[receipt.address] = receipt;
+    window.localStorage.setItem(receiptsStorageKey, JSON.stringify(receipts));
+    DOM.receipt.value = JSON.stringify(receipt, null, 2);
+    DOM.receiptResult.textContent = "Receipt saved, keep a copy of it";
+}
+
+function showSavedReceipt(address) {
+    let receipt = loadReceipts()[address];
+    if (receipt) {
+        DOM.receipt.value = JSON.stringify(receipt, null, 2);
+        DOM.receiptResult.textContent = "Saved receipt for " + address;
+    }
+}
+
+function checkReceipt() {
+    let receiptJson = DOM.receipt.value.trim();
+    let serverKey = DOM.serverKey.value.trim();
+    if (receiptJson.length == 0) {
+        DOM.receiptResult.textContent = "Empty receipt";
+        return;
+    }
+    if (serverKey.length == 0) {
+        let msg = "Enter the server public key published by MaidSafe";
+        msg += " to verify the receipt";
+        DOM.receiptResult.textContent = msg;
+        return;
+    }
+    verifyReceipt(receiptJson, serverKey)
+        .then(() => {
+            let r = JSON.parse(receiptJson);
+            let submitted = new Date(r.timestamp * 1000);
+            let msg = "Valid receipt: public key " + r.pkhex;
+            msg += " submitted for " + r.address;
+            msg += " at " + submitted.toLocaleString();
+            DOM.receiptResult.textContent = msg;
+        })
+        .catch((e) => {
+            DOM.receiptResult.textContent = "Receipt is not valid: " + e;
+        });
+}
+
+function apiErrorMessage(resp) {
+    let code = resp.data && resp.data.code;
+    if (code in apiErrors) {
+        return apiErrors[code];
+    }
+    return "Unexpected response from server (" + resp.status + ")";
+}
+
 function showPublicKey(keypair) {
     let pkBuffer = keypair.getPublicKeyBuffer();
     let pkHex = bufferToHex(pkBuffer);
@@ -192,7 +330,7 @@ function showPublicKey(keypair) {
     showAddress(keypair);
 }
 
-function keyToP2wpkhAddress(pk) {
+function keyToP2shP2wpkhAddress(pk) {
     let keyhash = bitcoinjs.bitcoin.crypto.hash160(pk.getPublicKeyBuffer());
     let scriptsig = bitcoinjs.bitcoin.script.witnessPubKeyHash.output.encode(keyhash);
     let addressbytes = bitcoinjs.bitcoin.crypto.hash160(scriptsig);
@@ -202,9 +340,21 @@ function keyToP2wpkhAddress(pk) {
     return address;
 }
 
+// native segwit, bc1q
+function keyToP2wpkhAddress(pk) {
+    let keyhash = bitcoinjs.bitcoin.crypto.hash160(pk.getPublicKeyBuffer());
+    let scriptpubkey = bitcoinjs.bitcoin.script.witnessPubKeyHash.output.encode(keyhash);
+    let network = bitcoinjs.bitcoin.networks.bitcoin;
+    let address = bitcoinjs.bitcoin.address.fromOutputScript(scriptpubkey, network);
+    return address;
+}
+
 function showAddress(pk) {
     let address = pk.getAddress();
-    if (DOM.p2wpkh.checked) {
+    if (DOM.p2shP2wpkh.checked) {
+        address = keyToP2shP2wpkhAddress(pk);
+    }
+    else if (DOM.p2wpkh.checked) {
         address = keyToP2wpkhAddress(pk);
     }
     DOM.address.textContent = address;
@@ -271,21 +421,26 @@ function findDistribution() {
     // find the possible addresses for this secret key
     let skWif = DOM.maidSecret.value;
     let sk = bitcoinjs.bitcoin.ECPair.fromWIF(skWif);
-    let p2pkh = sk.getAddress();
-    let p2wpkh = keyToP2wpkhAddress(sk);
+    let addresses = [sk.getAddress()];
+    // segwit addresses only exist for compressed keys
+    if (sk.compressed) {
+        addresses.push(keyToP2shP2wpkhAddress(sk));
+        addresses.push(keyToP2wpkhAddress(sk));
+    }
     // find the distribution for these addresses
     let encryptedDistribution = "";
-    if (p2pkh in distributionList) {
-        encryptedDistribution = distributionList[p2pkh];
-    }
-    if (p2wpkh in distributionList) {
-        encryptedDistribution = distributionList[p2wpkh];
+    let address = "";
+    for (let i=0; i<addresses.length; i++) {
+        if (addresses[i] in distributionList) {
+            encryptedDistribution = distributionList[addresses[i]];
+            address = addresses[i];
+        }
     }
     // show error if no distribution found
     if (encryptedDistribution == "") {
         let msg = "No distribution for this key. Checked "
         msg += sizeofarray(distributionList) + " distributions for ";
-        msg += p2pkh + " and " + p2wpkh;
+        msg += addresses.join(" and ");
         DOM.distributionResult.textContent = msg;
         return;
     }
@@ -293,17 +448,34 @@ function findDistribution() {
     let distribution = decryptDistribution(skWif, encryptedDistribution)
         .then((distribution) => {
             if (distribution.length > 0) {
-                DOM.distributionResult.textContent = "Decrypted 1 distribution";
                 let d = JSON.parse(distribution);
+                // version 0 distributions don't include the address
+                if (d.maid_address != "" && d.maid_address != address) {
+                    let msg = "Distribution listed for " + address;
+                    msg += " is for a different address " + d.maid_address;
+                    DOM.distributionResult.textContent = msg;
+                    return;
+                }
+                DOM.distributionResult.textContent = "Decrypted 1 distribution";
                 showDistribution(d);
             }
             else {
                 DOM.distributionResult.textContent = "Error decrypting distribution";
             }
+        })
+        .catch((e) => {
+            DOM.distributionResult.textContent = "Error decrypting distribution: " + e;
         });
 }
 
 function showDistribution(d) {
+    let details = "";
+    if (d.version > 0) {
+        details = d.amount + " MAID at " + d.maid_address;
+        details += " in snapshot at block " + d.snapshot_block_height;
+        details += " for network " + d.network_id;
+    }
+    DOM.distributionDetails.textContent = details;
     DOM.receiveSk.textContent = d.secret_key;
     DOM.receiveTransfer.textContent = d.transfer;
 }
@@ -314,8 +486,11 @@ function init() {
     DOM.publicKey.addEventListener("input", publicKeyChanged);
     DOM.compressed.addEventListener("change", pkTypeChanged);
     DOM.uncompressed.addEventListener("change", pkTypeChanged);
+    DOM.p2shP2wpkh.addEventListener("change", pkTypeChanged);
     DOM.p2wpkh.addEventListener("change", pkTypeChanged);
     DOM.submit.addEventListener("click", submit);
+    DOM.check.addEventListener("click", checkSubmitted);
+    DOM.verifyReceipt.addEventListener("click", checkReceipt);
     DOM.listFile.addEventListener("change", loadListFile);
     DOM.maidSecret.addEventListener("input", findDistribution);
     DOM.clear.forEach((e) => {
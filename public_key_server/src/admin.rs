@@ -0,0 +1,329 @@
+use crate::api::{ErrorCode, ReqError};
+use crate::store::{self, KeyRecord, SubmissionMethod};
+use crate::{AddressKey, State};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tide::prelude::*;
+use tide::{Middleware, Next, Request, Response};
+
+// The admin api is only served when this is set. Requests must have the
+// header Authorization: Bearer <token>
+const ADMIN_TOKEN_ENV: &str = "ADMIN_TOKEN";
+
+// Short tokens can be guessed even with the rate limit
+const MIN_TOKEN_LENGTH: usize = 32;
+
+// Number of submissions listed in stats
+const RECENT_SUBMISSIONS: usize = 20;
+
+// Most log entries returned by one request, followers ask again for the rest
+pub const MAX_LOG_ENTRIES: usize = 1000;
+
+pub fn token_from_env() -> Result<Option<String>, String> {
+    let token = match std::env::var(ADMIN_TOKEN_ENV) {
+        Ok(t) if !t.trim().is_empty() => t.trim().to_string(),
+        _ => return Ok(None),
+    };
+    if token.len() < MIN_TOKEN_LENGTH {
+        return Err(format!("{} must be at least {} characters", ADMIN_TOKEN_ENV, MIN_TOKEN_LENGTH));
+    }
+    Ok(Some(token))
+}
+
+// Compares every byte so the time taken doesn't show how much of the token
+// was right
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Rejects requests without the admin token
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: Arc<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: &str) -> AdminAuth {
+        AdminAuth {
+            token: Arc::new(token.to_string()),
+        }
+    }
+
+    fn authorized(&self, header: Option<&str>) -> bool {
+        match header.and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(t) => tokens_match(t.trim().as_bytes(), self.token.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AdminAuth {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let header = req.header("Authorization").map(|h| h.last().as_str().to_string());
+        if !self.authorized(header.as_deref()) {
+            return Ok(ReqError::new(ErrorCode::Unauthorized, "Missing or invalid admin token").response());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    // jsonl (the default) or tar
+    format: Option<String>,
+    // export the keys at the close of this round rather than the latest
+    round: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ExportLine<'a> {
+    address: &'a str,
+    #[serde(flatten)]
+    record: &'a KeyRecord,
+}
+
+// The keys directory layout read by distribute, as a tarball
+fn keys_tar(keys: &[(String, String)]) -> std::io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mtime = store::now();
+    for (address, pkhex) in keys {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(pkhex.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, format!("keys/{}", address), pkhex.as_bytes())?;
+    }
+    builder.into_inner()
+}
+
+// GET /admin/v1/export?format=jsonl|tar&round=<id>
+// jsonl has the full record for each address, or only address and pkhex for
+// a round. tar has a keys directory with one file per address.
+pub async fn export(req: Request<State>) -> tide::Result {
+    let q: ExportQuery = match req.query() {
+        Ok(q) => q,
+        Err(_) => return Ok(ReqError::new(ErrorCode::InvalidRequest, "Invalid export options").response()),
+    };
+    let state = req.state();
+    let round = match q.round {
+        Some(id) => match state.rounds.get(id) {
+            Some(r) => Some(r.clone()),
+            None => return Ok(ReqError::new(ErrorCode::NotFound, "No round with this id").response()),
+        },
+        None => None,
+    };
+    // check again since addresses become filenames
+    let records: Vec<(String, KeyRecord)> = state.store.records().into_iter()
+        .filter(|(address, r)| distribution_core::validate_bitcoin_pair(address, &r.pkhex).is_ok())
+        .collect();
+    let keys: Vec<(String, String)> = match &round {
+        Some(r) => state.store.keys_at(r.closes).into_iter()
+            .filter(|(address, pkhex)| distribution_core::validate_bitcoin_pair(address, pkhex).is_ok())
+            .collect(),
+        None => records.iter().map(|(a, r)| (a.clone(), r.pkhex.clone())).collect(),
+    };
+    let mut res = Response::new(200);
+    match q.format.as_deref().unwrap_or("jsonl") {
+        "jsonl" => {
+            let lines: Vec<String> = match round {
+                Some(_) => keys.iter().map(|(address, pkhex)| json!({ "address": address, "pkhex": pkhex }).to_string()).collect(),
+                None => records.iter().map(|(address, record)| json!(ExportLine { address, record }).to_string()).collect(),
+            };
+            let body: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+            res.set_body(body);
+            res.set_content_type("application/jsonl");
+        }
+        "tar" => {
+            let tarball = match keys_tar(&keys) {
+                Ok(t) => t,
+                Err(_) => return Ok(ReqError::new(ErrorCode::StorageError, "Error creating tarball").response()),
+            };
+            res.set_body(tarball);
+            res.set_content_type("application/x-tar");
+            res.insert_header("Content-Disposition", "attachment; filename=\"keys.tar\"");
+        }
+        _ => return Ok(ReqError::new(ErrorCode::InvalidRequest, "Format must be jsonl or tar").response()),
+    }
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    // the seq of the first entry, 0 if not given
+    from: Option<u64>,
+    limit: Option<usize>,
+}
+
+// GET /admin/v1/log?from=<seq>&limit=<n>
+// Log entries in order as json lines, for followers to copy and check
+pub async fn get_log(req: Request<State>) -> tide::Result {
+    let q: LogQuery = match req.query() {
+        Ok(q) => q,
+        Err(_) => return Ok(ReqError::new(ErrorCode::InvalidRequest, "Invalid log options").response()),
+    };
+    let limit = q.limit.unwrap_or(MAX_LOG_ENTRIES).min(MAX_LOG_ENTRIES);
+    let entries = match req.state().store.log_entries(q.from.unwrap_or(0), limit) {
+        Ok(e) => e,
+        Err(e) => return Ok(ReqError::new(ErrorCode::StorageError, &e).response()),
+    };
+    let body: String = entries.iter().map(|e| format!("{}\n", json!(e))).collect();
+    let mut res = Response::new(200);
+    res.set_body(body);
+    res.set_content_type("application/jsonl");
+    Ok(res)
+}
+
+// Counts by method and round, and the latest submissions
+fn stats(records: &[(String, KeyRecord)], recent: usize) -> serde_json::Value {
+    let mut methods = BTreeMap::<String, usize>::new();
+    let mut rounds = BTreeMap::<String, usize>::new();
+    for (_, r) in records {
+        let method = json!(r.method).as_str().unwrap_or("").to_string();
+        *methods.entry(method).or_insert(0) += 1;
+        let round = r.round.map(|id| id.to_string()).unwrap_or("none".to_string());
+        *rounds.entry(round).or_insert(0) += 1;
+    }
+    let mut latest: Vec<&(String, KeyRecord)> = records.iter().collect();
+    latest.sort_by_key(|r| std::cmp::Reverse(r.1.last_seen));
+    let latest: Vec<serde_json::Value> = latest.into_iter().take(recent)
+        .map(|(address, record)| json!(ExportLine { address, record }))
+        .collect();
+    json!({
+        "count": records.len(),
+        "flagged": records.iter().filter(|(_, r)| r.flagged).count(),
+        "methods": methods,
+        "rounds": rounds,
+        "recent": latest,
+    })
+}
+
+// GET /admin/v1/stats
+pub async fn get_stats(req: Request<State>) -> tide::Result {
+    let records = req.state().store.records();
+    let mut res = Response::new(200);
+    res.set_body(stats(&records, RECENT_SUBMISSIONS));
+    Ok(res)
+}
+
+// DELETE /admin/v1/keys/:address
+pub async fn delete_key(req: Request<State>) -> tide::Result {
+    let address = distribution_core::canonical_address(req.param("address")?);
+    match req.state().store.delete(&address) {
+        Ok(true) => {
+            let mut res = Response::new(200);
+            res.set_body(json!({ "deleted": address }));
+            Ok(res)
+        }
+        Ok(false) => Ok(ReqError::new(ErrorCode::NotFound, "No public key for this address").response()),
+        Err(e) => Ok(ReqError::new(ErrorCode::StorageError, &e).response()),
+    }
+}
+
+// Pairs from a json list or json lines, so an export can be imported
+fn parse_import(body: &str) -> Result<Vec<AddressKey>, String> {
+    if body.trim_start().starts_with('[') {
+        return match serde_json::from_str(body) {
+            Ok(aks) => Ok(aks),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    let mut aks = Vec::<AddressKey>::new();
+    for (i, line) in body.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(ak) => aks.push(ak),
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    Ok(aks)
+}
+
+// POST /admin/v1/keys/import with a json list or json lines of address and
+// pkhex, eg keys found by distribute scan-keys.
+// Addresses that already have a key are left as they are.
+pub async fn import_keys(mut req: Request<State>) -> tide::Result {
+    let body = req.body_string().await.unwrap_or_default();
+    let aks = match parse_import(&body) {
+        Ok(aks) => aks,
+        Err(e) => return Ok(ReqError::new(ErrorCode::InvalidRequest, &format!("Body must be json address and pkhex pairs: {}", e)).response()),
+    };
+    let mut imported = 0;
+    let mut existing = 0;
+    let mut rejected = Vec::<serde_json::Value>::new();
+    for ak in aks {
+        let address = &distribution_core::canonical_address(&ak.address);
+        let pkhex = ak.pkhex.trim().to_lowercase();
+        if let Err(e) = distribution_core::validate_bitcoin_pair(address, &pkhex) {
+            rejected.push(json!({ "address": address, "error": e.message() }));
+            continue;
+        }
+        match req.state().store.import_key(address, &pkhex, SubmissionMethod::Scan, store::now()) {
+            Ok(true) => imported += 1,
+            Ok(false) => existing += 1,
+            Err(e) => return Ok(ReqError::new(ErrorCode::StorageError, e).response()),
+        }
+    }
+    let mut res = Response::new(200);
+    res.set_body(json!({ "imported": imported, "existing": existing, "rejected": rejected }));
+    Ok(res)
+}
+
+pub fn tests() {
+    // token checks
+    {
+        let auth = AdminAuth::new("0123456789abcdef0123456789abcdef");
+        assert!(auth.authorized(Some("Bearer 0123456789abcdef0123456789abcdef")), "Valid token was not authorized");
+        assert!(!auth.authorized(Some("Bearer 0123456789abcdef0123456789abcdee")), "Wrong token was authorized");
+        assert!(!auth.authorized(Some("Bearer 0123456789abcdef")), "Partial token was authorized");
+        assert!(!auth.authorized(Some("0123456789abcdef0123456789abcdef")), "Token without Bearer was authorized");
+        assert!(!auth.authorized(None), "Missing token was authorized");
+    }
+    // import accepts a list or json lines
+    {
+        let list = r#"[{"address": "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "pkhex": "02"}]"#;
+        assert!(parse_import(list).map(|aks| aks.len()) == Ok(1), "Import list not parsed");
+        let lines = "{\"address\": \"a\", \"pkhex\": \"02\", \"submissions\": 3}\n\n{\"address\": \"b\", \"pkhex\": \"03\"}\n";
+        assert!(parse_import(lines).map(|aks| aks.len()) == Ok(2), "Import lines not parsed");
+        assert!(parse_import("{\"address\": \"a\"}").is_err(), "Import without pkhex did not return error");
+    }
+    // stats
+    {
+        let record = |last_seen: u64, method: SubmissionMethod, round: Option<u32>| KeyRecord {
+            pkhex: "02".to_string(),
+            first_seen: 1000,
+            last_seen,
+            method,
+            submissions: 1,
+            flagged: round.is_none(),
+            round,
+        };
+        let records = vec![
+            ("a".to_string(), record(1000, SubmissionMethod::PublicKey, Some(1))),
+            ("b".to_string(), record(3000, SubmissionMethod::Scan, None)),
+            ("c".to_string(), record(2000, SubmissionMethod::PublicKey, Some(1))),
+        ];
+        let s = stats(&records, 2);
+        assert!(s["count"] == json!(3) && s["flagged"] == json!(1), "Stats are {}", s);
+        assert!(s["methods"]["public_key"] == json!(2) && s["methods"]["scan"] == json!(1), "Stats by method are {}", s["methods"]);
+        assert!(s["rounds"]["1"] == json!(2) && s["rounds"]["none"] == json!(1), "Stats by round are {}", s["rounds"]);
+        let recent: Vec<&str> = s["recent"].as_array().unwrap().iter().map(|r| r["address"].as_str().unwrap()).collect();
+        assert!(recent == vec!["b", "c"], "Recent submissions are {:?}", recent);
+    }
+    // tarball has a keys directory
+    {
+        let keys = vec![("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH".to_string(), "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string())];
+        let tarball = keys_tar(&keys).unwrap();
+        let mut archive = tar::Archive::new(&tarball[..]);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        let path = entry.path().unwrap().to_string_lossy().to_string();
+        assert!(path == "keys/1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "Tarball entry is {}", path);
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+        assert!(content == keys[0].1, "Tarball key is {}", content);
+        assert!(entries.next().is_none(), "Tarball has extra entries");
+    }
+}
@@ -0,0 +1,388 @@
+use crate::holders::Eligibility;
+use crate::signed_message::public_key_from_signature;
+use crate::store::{self, KeyRecord, SubmissionMethod};
+use crate::{submission_round, validate_bitcoin_pair, AddressKey, State};
+use distribution_core::{canonical_address, Receipt, ValidationError};
+use tide::prelude::*;
+use tide::{Request, Response};
+
+// Most keys that can be submitted in one batch request
+const MAX_BATCH: usize = 100;
+
+// Errors returned by the json api, so clients can handle each case without
+// matching on the message.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidPublicKey,
+    InvalidAddress,
+    InvalidNetwork,
+    KeyMismatch,
+    // the address has no MAID balance in the snapshot
+    NotEligible,
+    // submissions are only accepted while a round is open
+    RoundClosed,
+    NotFound,
+    BatchTooLarge,
+    StorageError,
+    // from the rate limit middleware, which isn't only for the api
+    RateLimited,
+    // admin api without the admin token
+    Unauthorized,
+    // a signed submission without an unexpired challenge for the address
+    NoChallenge,
+    // a signed submission whose signature isn't for a challenge
+    InvalidSignature,
+}
+
+// The codes a submission can be rejected with, counted in /metrics
+pub const SUBMISSION_ERRORS: [ErrorCode; 9] = [
+    ErrorCode::InvalidPublicKey,
+    ErrorCode::InvalidAddress,
+    ErrorCode::InvalidNetwork,
+    ErrorCode::KeyMismatch,
+    ErrorCode::NotEligible,
+    ErrorCode::RoundClosed,
+    ErrorCode::StorageError,
+    ErrorCode::NoChallenge,
+    ErrorCode::InvalidSignature,
+];
+
+impl ErrorCode {
+    // The code as it's named in json, eg for counting errors
+    pub fn reason(self) -> String {
+        json!(self).as_str().unwrap_or("").to_string()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ReqError {
+    code: ErrorCode,
+    error: String,
+}
+
+impl ReqError {
+    pub fn new(code: ErrorCode, error: &str) -> ReqError {
+        ReqError {
+            code,
+            error: error.to_string(),
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self.code {
+            ErrorCode::NotEligible => 403,
+            ErrorCode::RoundClosed => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::BatchTooLarge => 413,
+            ErrorCode::StorageError => 500,
+            ErrorCode::RateLimited => 429,
+            ErrorCode::Unauthorized => 401,
+            _ => 400,
+        }
+    }
+
+    pub fn reason(&self) -> String {
+        self.code.reason()
+    }
+
+    pub fn response(&self) -> Response {
+        let mut res = Response::new(self.status());
+        res.set_body(json!(self));
+        res
+    }
+
+    // The message as plain text, for the html form
+    pub fn text_response(&self) -> Response {
+        let mut res = Response::new(self.status());
+        res.set_body(self.error.as_str());
+        res
+    }
+}
+
+// The code for each error from validate_bitcoin_pair
+fn validation_error(error: ValidationError) -> ReqError {
+    let code = match error {
+        ValidationError::InvalidPublicKey => ErrorCode::InvalidPublicKey,
+        ValidationError::InvalidAddress => ErrorCode::InvalidAddress,
+        ValidationError::InvalidNetwork => ErrorCode::InvalidNetwork,
+        ValidationError::KeyMismatch => ErrorCode::KeyMismatch,
+    };
+    ReqError::new(code, error.message())
+}
+
+#[derive(Serialize)]
+pub struct KeyResponse {
+    address: String,
+    #[serde(flatten)]
+    record: KeyRecord,
+    // null when the server has no snapshot
+    eligible: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<String>,
+    // signed proof of the submission, only in responses to a submission
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<Receipt>,
+}
+
+impl KeyResponse {
+    fn new(address: String, record: KeyRecord, eligibility: Eligibility) -> KeyResponse {
+        KeyResponse {
+            address,
+            record,
+            eligible: eligibility.eligible,
+            balance: eligibility.balance,
+            receipt: None,
+        }
+    }
+
+    // The saved submission as plain text, for the html form
+    pub fn text(&self) -> String {
+        let eligibility = Eligibility {
+            eligible: self.eligible,
+            balance: self.balance.clone(),
+        };
+        format!(
+            "Success\nAddress: {}\nPublic Key: {}\nEligible: {}\nReceipt: {}",
+            self.address,
+            self.record.pkhex,
+            eligibility.describe(),
+            json!(self.receipt),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<KeyResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ReqError>,
+}
+
+fn json_response(status: u16, body: impl Serialize) -> Response {
+    let mut res = Response::new(status);
+    res.set_body(json!(body));
+    res
+}
+
+// Validates and saves a pair given with its public key
+pub fn save_key(state: &State, ak: &AddressKey, method: SubmissionMethod) -> Result<KeyResponse, ReqError> {
+    counted(state, validate_and_save_key(state, ak, method))
+}
+
+// Recovers the public key from a signature of a challenge for the address,
+// then validates and saves the pair
+pub fn save_signed_key(state: &State, address: &str, signature: &str) -> Result<KeyResponse, ReqError> {
+    counted(state, recover_and_save_key(state, address, signature))
+}
+
+// Every submission's result is counted for /metrics here
+fn counted(state: &State, result: Result<KeyResponse, ReqError>) -> Result<KeyResponse, ReqError> {
+    match &result {
+        Ok(_) => state.metrics.accepted(),
+        Err(e) => state.metrics.rejected(e.code),
+    }
+    result
+}
+
+fn recover_and_save_key(state: &State, address: &str, signature: &str) -> Result<KeyResponse, ReqError> {
+    let messages = state.challenges.lock().unwrap().messages(address);
+    if messages.is_empty() {
+        return Err(ReqError::new(ErrorCode::NoChallenge, "No challenge for this address, it may have expired"));
+    }
+    // the signature can be for any challenge issued for the address, the
+    // error is for the latest one since that's most likely the one signed
+    let mut recovered = public_key_from_signature(address, &messages[0], signature);
+    for message in &messages[1..] {
+        if recovered.is_ok() {
+            break;
+        }
+        if let Ok(r) = public_key_from_signature(address, message, signature) {
+            recovered = Ok(r);
+        }
+    }
+    let (pk, method) = match recovered {
+        Ok(r) => r,
+        Err(e) => return Err(ReqError::new(ErrorCode::InvalidSignature, e)),
+    };
+    let ak = AddressKey {
+        address: address.to_string(),
+        pkhex: pk.to_string(),
+    };
+    // the round and snapshot may have changed since the challenge
+    let key = validate_and_save_key(state, &ak, method)?;
+    state.challenges.lock().unwrap().remove(&ak.address);
+    Ok(key)
+}
+
+// The same key can be sent with different case or spacing, so it's saved in
+// one form
+fn normalized(ak: &AddressKey) -> AddressKey {
+    AddressKey {
+        address: canonical_address(&ak.address),
+        pkhex: ak.pkhex.trim().to_lowercase(),
+    }
+}
+
+fn validate_and_save_key(state: &State, ak: &AddressKey, method: SubmissionMethod) -> Result<KeyResponse, ReqError> {
+    let ak = normalized(ak);
+    if let Err(e) = validate_bitcoin_pair(&ak) {
+        return Err(validation_error(e));
+    }
+    let time = store::now();
+    let round = match submission_round(state, time) {
+        Ok(r) => r,
+        Err(e) => return Err(ReqError::new(ErrorCode::RoundClosed, e)),
+    };
+    let eligibility = state.holders.eligibility(&ak.address);
+    if state.holders.rejects(&eligibility) {
+        return Err(ReqError::new(ErrorCode::NotEligible, "Address has no MAID balance in the snapshot"));
+    }
+    let flagged = eligibility.eligible == Some(false);
+    match state.store.record_at(&ak.address, &ak.pkhex, method, flagged, round, time) {
+        Ok(record) => {
+            let receipt = state.receipt_key.sign(&ak.address, &record.pkhex, record.last_seen);
+            let mut key = KeyResponse::new(ak.address, record, eligibility);
+            key.receipt = Some(receipt);
+            Ok(key)
+        }
+        Err(e) => Err(ReqError::new(ErrorCode::StorageError, e)),
+    }
+}
+
+// POST /api/v1/keys with {"address": "...", "pkhex": "..."}
+pub async fn submit_key(mut req: Request<State>) -> tide::Result {
+    let ak: AddressKey = match req.body_json().await {
+        Ok(ak) => ak,
+        Err(_) => return Ok(ReqError::new(ErrorCode::InvalidRequest, "Body must be json with address and pkhex").response()),
+    };
+    match save_key(req.state(), &ak, SubmissionMethod::PublicKey) {
+        Ok(key) => Ok(json_response(200, key)),
+        Err(e) => Ok(e.response()),
+    }
+}
+
+// GET /api/v1/keys/:address
+pub async fn get_key(req: Request<State>) -> tide::Result {
+    let address = canonical_address(req.param("address")?);
+    match req.state().store.get(&address) {
+        Some(record) => {
+            let eligibility = req.state().holders.eligibility(&address);
+            Ok(json_response(200, KeyResponse::new(address, record, eligibility)))
+        }
+        None => Ok(ReqError::new(ErrorCode::NotFound, "No public key for this address").response()),
+    }
+}
+
+// GET /api/v1/receipt_key, the public key receipts are signed with.
+// Receipts should be checked against the published key rather than this, but
+// it helps to compare the two.
+pub async fn get_receipt_key(req: Request<State>) -> tide::Result {
+    let server_key = req.state().receipt_key.public_key().to_string();
+    Ok(json_response(200, json!({ "server_key": server_key })))
+}
+
+// GET /api/v1/log/head, the number of log entries and the hash of the last.
+// Anyone who saves the head can later check a copy of the log hasn't been
+// changed before it.
+pub async fn get_log_head(req: Request<State>) -> tide::Result {
+    match req.state().store.log_head() {
+        Ok(head) => Ok(json_response(200, head)),
+        Err(e) => Ok(ReqError::new(ErrorCode::StorageError, &e).response()),
+    }
+}
+
+// POST /api/v1/keys/batch with a list of pairs.
+// Each pair is saved or rejected on its own, the results are in the same
+// order as the request.
+pub async fn submit_batch(mut req: Request<State>) -> tide::Result {
+    let aks: Vec<AddressKey> = match req.body_json().await {
+        Ok(aks) => aks,
+        Err(_) => return Ok(ReqError::new(ErrorCode::InvalidRequest, "Body must be a json list of address and pkhex").response()),
+    };
+    if aks.len() > MAX_BATCH {
+        let msg = format!("At most {} keys can be submitted at once", MAX_BATCH);
+        return Ok(ReqError::new(ErrorCode::BatchTooLarge, &msg).response());
+    }
+    let results: Vec<BatchResult> = aks.iter().map(|ak| match save_key(req.state(), ak, SubmissionMethod::PublicKey) {
+        Ok(key) => BatchResult {
+            address: ak.address.clone(),
+            key: Some(key),
+            error: None,
+        },
+        Err(e) => BatchResult {
+            address: ak.address.clone(),
+            key: None,
+            error: Some(e),
+        },
+    }).collect();
+    Ok(json_response(200, json!({ "results": results })))
+}
+
+pub fn tests() {
+    // each validation error has its own code
+    {
+        let cases = [
+            ("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "02", ErrorCode::InvalidPublicKey),
+            ("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMX", "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", ErrorCode::InvalidAddress),
+            ("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", ErrorCode::InvalidNetwork),
+            ("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", ErrorCode::KeyMismatch),
+        ];
+        for (address, pkhex, code) in cases {
+            let e = distribution_core::validate_bitcoin_pair(address, pkhex).unwrap_err();
+            let r = validation_error(e);
+            assert!(r.code == code, "{} {} gave code {:?}", address, pkhex, r.code);
+            assert!(r.reason() == e.reason(), "Code {} is not the validation reason {}", r.reason(), e.reason());
+            assert!(SUBMISSION_ERRORS.contains(&r.code), "Code {} is not counted", r.reason());
+        }
+    }
+    // errors are json with a code and message
+    {
+        let e = ReqError::new(ErrorCode::NotFound, "No public key for this address");
+        let j = json!(e).to_string();
+        assert!(j == r#"{"code":"not_found","error":"No public key for this address"}"#, "Error json is {}", j);
+        assert!(e.status() == 404, "Not found error has status {}", e.status());
+    }
+    // eligibility is in key responses
+    {
+        let record = KeyRecord {
+            pkhex: "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string(),
+            first_seen: 1000,
+            last_seen: 1000,
+            method: SubmissionMethod::PublicKey,
+            submissions: 1,
+            flagged: false,
+            round: None,
+        };
+        let eligibility = Eligibility {
+            eligible: Some(true),
+            balance: Some("10".to_string()),
+        };
+        let j = json!(KeyResponse::new("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH".to_string(), record.clone(), eligibility));
+        assert!(j["eligible"] == json!(true) && j["balance"] == json!("10"), "Key response is {}", j);
+        let unknown = Eligibility {
+            eligible: None,
+            balance: None,
+        };
+        let j = json!(KeyResponse::new("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH".to_string(), record, unknown));
+        assert!(j["eligible"].is_null() && j.get("balance").is_none(), "Key response without snapshot is {}", j);
+    }
+    // keys are saved in one form however they're sent
+    {
+        let ak = AddressKey {
+            address: " 1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH\n".to_string(),
+            pkhex: " 0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798 ".to_string(),
+        };
+        let ak = normalized(&ak);
+        assert!(ak.address == "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "Normalized address is {:?}", ak.address);
+        assert!(ak.pkhex == "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", "Normalized key is {:?}", ak.pkhex);
+        let ak = normalized(&AddressKey {
+            address: "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4".to_string(),
+            pkhex: ak.pkhex,
+        });
+        assert!(ak.address == "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "Normalized bech32 address is {:?}", ak.address);
+    }
+}
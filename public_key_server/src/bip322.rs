@@ -0,0 +1,246 @@
+use bitcoin::absolute::LockTime;
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::{Builder, PushBytesBuf};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::sighash::SighashCache;
+use bitcoin::transaction::Version;
+use bitcoin::{ecdsa, Address, Amount, Network, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use secp256k1::{Message, Secp256k1};
+use std::str::FromStr;
+
+// BIP322 generic signed messages, for segwit wallets that can't use the
+// legacy signmessage format.
+// Only p2wpkh and p2sh-p2wpkh are supported since those are the only segwit
+// addresses with a public key that distributions can be encrypted to.
+
+fn message_hash(message: &str) -> [u8; 32] {
+    let tag = sha256::Hash::hash(b"BIP0322-signed-message");
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    engine.input(message.as_bytes());
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// The virtual transaction that pays to the address
+fn to_spend(script_pubkey: &ScriptBuf, message: &str) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0xFFFFFFFF,
+            },
+            script_sig: Builder::new().push_int(0).push_slice(message_hash(message)).into_script(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.clone(),
+        }],
+    }
+}
+
+// The virtual transaction the address signs, spending to_spend
+fn to_sign(to_spend: &Transaction, script_sig: ScriptBuf, witness: Witness) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.txid(),
+                vout: 0,
+            },
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+// p2sh-p2wpkh spends put the p2wpkh script in the scriptSig
+fn p2sh_p2wpkh_script_sig(pk: &PublicKey) -> ScriptBuf {
+    let redeem_script = ScriptBuf::new_p2wpkh(&pk.wpubkey_hash().unwrap());
+    let push = PushBytesBuf::try_from(redeem_script.into_bytes()).unwrap();
+    Builder::new().push_slice(push).into_script()
+}
+
+// Verifies a BIP322 simple (encoded witness) or full (encoded to_sign
+// transaction) signature and returns the public key from the witness.
+pub fn verify(address: &str, message: &str, signature: &[u8]) -> Result<PublicKey, &'static str> {
+    let addr = match Address::from_str(address) {
+        Ok(a) => a,
+        Err(_) => return Err("Invalid address"),
+    };
+    let addr = match addr.require_network(Network::Bitcoin) {
+        Ok(a) => a,
+        Err(_) => return Err("Invalid network"),
+    };
+    let script_pubkey = addr.script_pubkey();
+    if !script_pubkey.is_p2wpkh() && !script_pubkey.is_p2sh() {
+        return Err("BIP322 signatures are only supported for p2wpkh and p2sh-p2wpkh addresses");
+    }
+    let to_spend = to_spend(&script_pubkey, message);
+    // simple
+    if let Ok(witness) = deserialize::<Witness>(signature) {
+        let pk = witness_public_key(&witness)?;
+        let script_sig = match script_pubkey.is_p2sh() {
+            true => p2sh_p2wpkh_script_sig(&pk),
+            false => ScriptBuf::new(),
+        };
+        return verify_to_sign(address, &script_pubkey, &to_sign(&to_spend, script_sig, witness));
+    }
+    // full
+    if let Ok(tx) = deserialize::<Transaction>(signature) {
+        if tx.input.len() != 1 || tx.input[0].previous_output != (OutPoint { txid: to_spend.txid(), vout: 0 }) {
+            return Err("Signature does not spend the message");
+        }
+        if tx.output.len() != 1 || tx.output[0].value != Amount::ZERO || tx.output[0].script_pubkey != Builder::new().push_opcode(OP_RETURN).into_script() {
+            return Err("Signature has invalid outputs");
+        }
+        return verify_to_sign(address, &script_pubkey, &tx);
+    }
+    Err("Invalid signature")
+}
+
+fn witness_public_key(witness: &Witness) -> Result<PublicKey, &'static str> {
+    if witness.len() != 2 {
+        return Err("Signature witness must be a signature and public key");
+    }
+    match PublicKey::from_slice(witness.nth(1).unwrap()) {
+        Ok(pk) if pk.compressed => Ok(pk),
+        _ => Err("Invalid public key"),
+    }
+}
+
+fn verify_to_sign(address: &str, script_pubkey: &ScriptBuf, to_sign: &Transaction) -> Result<PublicKey, &'static str> {
+    let input = &to_sign.input[0];
+    let pk = witness_public_key(&input.witness)?;
+    // the key from the witness must be for the address
+    if let Err(e) = distribution_core::validate_bitcoin_pair(address, &pk.to_string()) {
+        return Err(e.message());
+    }
+    let expected_script_sig = match script_pubkey.is_p2sh() {
+        true => p2sh_p2wpkh_script_sig(&pk),
+        false => ScriptBuf::new(),
+    };
+    if input.script_sig != expected_script_sig {
+        return Err("Invalid scriptSig for address");
+    }
+    let sig = match ecdsa::Signature::from_slice(input.witness.nth(0).unwrap()) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid signature"),
+    };
+    let p2wpkh = ScriptBuf::new_p2wpkh(&pk.wpubkey_hash().unwrap());
+    let sighash = match SighashCache::new(to_sign).p2wpkh_signature_hash(0, &p2wpkh, Amount::ZERO, sig.hash_ty) {
+        Ok(h) => h,
+        Err(_) => return Err("Invalid signature"),
+    };
+    let msg = Message::from_digest(sighash.to_byte_array());
+    match Secp256k1::verification_only().verify_ecdsa(&msg, &sig.sig, &pk.inner) {
+        Ok(()) => Ok(pk),
+        Err(_) => Err("Signature does not verify"),
+    }
+}
+
+pub fn tests() {
+    // test vectors from BIP322
+    let address = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    let pkhex = "02c7f12003196442943d8588e01aee840423cc54fc1521526a3b85c2b0cbd58872";
+    let script_pubkey = Address::from_str(address).unwrap().assume_checked().script_pubkey();
+    let decode = |s: &str| {
+        use bitcoin::base64::prelude::{Engine as _, BASE64_STANDARD};
+        BASE64_STANDARD.decode(s).unwrap()
+    };
+    // message hashes
+    {
+        let h = hex::encode(message_hash(""));
+        assert!(h == "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1", "Empty message hash is {}", h);
+        let h = hex::encode(message_hash("Hello World"));
+        assert!(h == "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a", "Hello World message hash is {}", h);
+    }
+    // transaction ids
+    {
+        let cases = [
+            ("", "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7", "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6"),
+            ("Hello World", "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b", "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"),
+        ];
+        for (message, to_spend_txid, to_sign_txid) in cases {
+            let spend = to_spend(&script_pubkey, message);
+            assert!(spend.txid().to_string() == to_spend_txid, "to_spend for {:?} is {}", message, spend.txid());
+            let sign = to_sign(&spend, ScriptBuf::new(), Witness::new());
+            assert!(sign.txid().to_string() == to_sign_txid, "to_sign for {:?} is {}", message, sign.txid());
+        }
+    }
+    // simple signatures
+    let empty_sig = decode("AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=");
+    let hello_sig = decode("AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=");
+    let hello_sig_2 = decode("AkgwRQIhAOzyynlqt93lOKJr+wmmxIens//zPzl9tqIOua93wO6MAiBi5n5EyAcPScOjf1lAqIUIQtr3zKNeavYabHyR8eGhowEhAsfxIAMZZEKUPYWI4BruhAQjzFT8FSFSajuFwrDL1Yhy");
+    {
+        for (message, sig) in [("", &empty_sig), ("Hello World", &hello_sig), ("Hello World", &hello_sig_2)] {
+            let pk = verify(address, message, sig);
+            assert!(pk.map(|pk| pk.to_string()) == Ok(pkhex.to_string()), "BIP322 vector for {:?} did not verify", message);
+        }
+    }
+    // signature for a different message
+    {
+        let r = verify(address, "", &hello_sig_2);
+        assert!(r == Err("Signature does not verify"), "Signature for a different message gave {:?}", r);
+    }
+    // signature for a different address
+    {
+        let r = verify("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "Hello World", &hello_sig);
+        assert!(r == Err("Public key does not match address"), "Signature for a different address gave {:?}", r);
+    }
+    // full signature is the whole to_sign transaction
+    {
+        let witness = deserialize::<Witness>(&hello_sig).unwrap();
+        let tx = to_sign(&to_spend(&script_pubkey, "Hello World"), ScriptBuf::new(), witness);
+        let full = bitcoin::consensus::serialize(&tx);
+        let pk = verify(address, "Hello World", &full);
+        assert!(pk.map(|pk| pk.to_string()) == Ok(pkhex.to_string()), "BIP322 full signature did not verify");
+        let r = verify(address, "", &full);
+        assert!(r == Err("Signature does not spend the message"), "Full signature for a different message gave {:?}", r);
+    }
+    // p2sh-p2wpkh, secret key 1
+    {
+        let secp = Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&[[0; 31].as_slice(), &[1]].concat()).unwrap();
+        let pk = PublicKey::new(sk.public_key(&secp));
+        let address = "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN";
+        let p2sh = Address::from_str(address).unwrap().assume_checked().script_pubkey();
+        let unsigned = to_sign(&to_spend(&p2sh, "Hello World"), p2sh_p2wpkh_script_sig(&pk), Witness::new());
+        let p2wpkh = ScriptBuf::new_p2wpkh(&pk.wpubkey_hash().unwrap());
+        let sighash = SighashCache::new(&unsigned).p2wpkh_signature_hash(0, &p2wpkh, Amount::ZERO, bitcoin::EcdsaSighashType::All).unwrap();
+        let sig = ecdsa::Signature::sighash_all(secp.sign_ecdsa(&Message::from_digest(sighash.to_byte_array()), &sk));
+        let witness = Witness::p2wpkh(&sig, &pk.inner);
+        let simple = bitcoin::consensus::serialize(&witness);
+        let r = verify(address, "Hello World", &simple);
+        assert!(r == Ok(pk), "BIP322 p2sh-p2wpkh signature gave {:?}", r);
+        let r = verify("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "Hello World", &simple);
+        assert!(r.is_err(), "BIP322 p2sh-p2wpkh signature verified as p2wpkh");
+    }
+    // taproot from the BIP322 vectors is not supported
+    {
+        let sig = decode("AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==");
+        let r = verify("bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3", "Hello World", &sig);
+        assert!(r.is_err(), "Taproot signature did not return error");
+    }
+    // p2pkh uses the legacy format
+    {
+        let r = verify("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "Hello World", &hello_sig);
+        assert!(r.is_err(), "BIP322 p2pkh signature did not return error");
+    }
+    // junk
+    {
+        let r = verify(address, "Hello World", &[1, 2, 3]);
+        assert!(r == Err("Invalid signature"), "Junk signature gave {:?}", r);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// seconds a challenge can be signed for before it must be requested again
+const CHALLENGE_LIFETIME: u64 = 600;
+// unexpired challenges kept for each address, the oldest is dropped when
+// another is requested. Each one is tried when a signature is submitted so
+// this limits the work for each submission.
+const MAX_CHALLENGES_PER_ADDRESS: usize = 5;
+// addresses with unexpired challenges, so requests for many addresses can't
+// use up the memory
+const MAX_CHALLENGE_ADDRESSES: usize = 100_000;
+
+struct Challenge {
+    message: String,
+    expires: u64,
+}
+
+// The messages each address can sign to prove it controls the address.
+// Anyone can request a challenge for any address, so the latest few are kept
+// until they expire rather than only the last one, otherwise requesting one
+// challenge for someone else's address would stop theirs from being
+// accepted. Once one is used they're all removed. These are in memory so a
+// restart means requesting a new challenge.
+#[derive(Default)]
+pub struct Challenges {
+    issued: HashMap<String, Vec<Challenge>>,
+    // when expired challenges were last removed
+    pruned_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl Challenges {
+    // Creates a new message for the address to sign. Returns an error if
+    // there are already challenges for too many addresses.
+    pub fn issue(&mut self, address: &str) -> Result<String, &'static str> {
+        self.issue_at(address, now())
+    }
+
+    fn issue_at(&mut self, address: &str, time: u64) -> Result<String, &'static str> {
+        // forget old challenges so they don't pile up, at most once a second
+        // since it looks at every address
+        if time > self.pruned_at {
+            for challenges in self.issued.values_mut() {
+                challenges.retain(|c| c.expires > time);
+            }
+            self.issued.retain(|_, challenges| !challenges.is_empty());
+            self.pruned_at = time;
+        }
+        if self.issued.len() >= MAX_CHALLENGE_ADDRESSES && !self.issued.contains_key(address) {
+            return Err("Too many challenges outstanding, try again later");
+        }
+        let nonce: [u8; 16] = rand::random();
+        let message = format!("MAID distribution public key for {} nonce {}", address, hex::encode(nonce));
+        let challenge = Challenge {
+            message: message.clone(),
+            expires: time + CHALLENGE_LIFETIME,
+        };
+        let challenges = self.issued.entry(address.to_string()).or_default();
+        if challenges.len() >= MAX_CHALLENGES_PER_ADDRESS {
+            challenges.remove(0);
+        }
+        challenges.push(challenge);
+        Ok(message)
+    }
+
+    // The messages the address can sign that haven't expired, latest first
+    pub fn messages(&self, address: &str) -> Vec<String> {
+        self.messages_at(address, now())
+    }
+
+    fn messages_at(&self, address: &str, time: u64) -> Vec<String> {
+        match self.issued.get(address) {
+            Some(challenges) => challenges.iter().rev()
+                .filter(|c| c.expires > time)
+                .map(|c| c.message.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Called once a challenge for the address has been used
+    pub fn remove(&mut self, address: &str) {
+        self.issued.remove(address);
+    }
+}
+
+pub fn tests() {
+    let address = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    // issued challenge can be used
+    {
+        let mut challenges = Challenges::default();
+        let message = challenges.issue_at(address, 1000).unwrap();
+        assert!(message.contains(address), "Challenge does not include the address: {}", message);
+        assert!(challenges.messages_at(address, 1000) == vec![message], "Issued challenge not found");
+        assert!(challenges.messages_at("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", 1000).is_empty(), "Challenge found for a different address");
+    }
+    // a later challenge doesn't replace earlier ones, up to the limit
+    {
+        let mut challenges = Challenges::default();
+        let first = challenges.issue_at(address, 1000).unwrap();
+        let second = challenges.issue_at(address, 1000).unwrap();
+        assert!(first != second, "Challenge was issued twice");
+        let messages = challenges.messages_at(address, 1001);
+        assert!(messages == vec![second.clone(), first.clone()], "Challenges are not latest first: {:?}", messages);
+        for _ in 0..100 {
+            challenges.issue_at(address, 1001).unwrap();
+        }
+        let messages = challenges.messages_at(address, 1001);
+        assert!(messages.len() == MAX_CHALLENGES_PER_ADDRESS, "{} challenges kept for the address", messages.len());
+        assert!(!messages.contains(&first) && !messages.contains(&second), "Oldest challenges were kept");
+    }
+    // challenges are only kept for so many addresses
+    {
+        let mut challenges = Challenges::default();
+        for i in 0..MAX_CHALLENGE_ADDRESSES {
+            challenges.issue_at(&i.to_string(), 1000).unwrap();
+        }
+        assert!(challenges.issue_at(address, 1000).is_err(), "Challenge issued past the limit");
+        assert!(challenges.issue_at("0", 1000).is_ok(), "Address with challenges can't have another");
+        // expired challenges make room
+        assert!(challenges.issue_at(address, 1000 + CHALLENGE_LIFETIME).is_ok(), "Expired challenges still count");
+    }
+    // challenges expire
+    {
+        let mut challenges = Challenges::default();
+        challenges.issue_at(address, 1000).unwrap();
+        assert!(challenges.messages_at(address, 1000 + CHALLENGE_LIFETIME).is_empty(), "Expired challenge can be used");
+        challenges.issue_at("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", 1000 + CHALLENGE_LIFETIME).unwrap();
+        assert!(challenges.issued.len() == 1, "Expired challenges were kept");
+    }
+    // challenges can only be used once
+    {
+        let mut challenges = Challenges::default();
+        challenges.issue_at(address, 1000).unwrap();
+        challenges.issue_at(address, 1000).unwrap();
+        challenges.remove(address);
+        assert!(challenges.messages_at(address, 1000).is_empty(), "Used challenge can be used again");
+    }
+}
@@ -0,0 +1,137 @@
+use crate::admin::MAX_LOG_ENTRIES;
+use crate::store::KeyStore;
+use crate::submission_log::{LogEntry, LogHead};
+use std::thread;
+use std::time::Duration;
+
+// Url of the server to follow, eg https://keys.example.com
+// When set this server copies every change from that server's log and
+// doesn't accept submissions, so it can take over if the other one fails.
+const FOLLOW_URL_ENV: &str = "FOLLOW_URL";
+
+// Seconds between checks for new log entries
+const FOLLOW_INTERVAL_SECS: u64 = 10;
+const FOLLOW_TIMEOUT_SECS: u64 = 60;
+
+pub fn url_from_env() -> Result<Option<String>, String> {
+    let url = match std::env::var(FOLLOW_URL_ENV) {
+        Ok(u) if !u.trim().is_empty() => u.trim().trim_end_matches('/').to_string(),
+        _ => return Ok(None),
+    };
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("{} must be an http or https url", FOLLOW_URL_ENV));
+    }
+    Ok(Some(url))
+}
+
+enum FollowError {
+    // the primary can't be reached, try again later
+    Unavailable(String),
+    // the logs don't match, or this store can't be written, so copying more
+    // would hide the problem
+    Stopped(String),
+}
+
+// Copies the log from the primary at url in a thread, using the primary's
+// admin token
+pub fn start(store: KeyStore, url: String, token: String) {
+    thread::spawn(move || follow(&store, &url, &token));
+}
+
+fn follow(store: &KeyStore, url: &str, token: &str) {
+    println!("Following {}", url);
+    loop {
+        match sync(store, url, token) {
+            // there may be more entries
+            Ok(n) if n == MAX_LOG_ENTRIES => continue,
+            Ok(_) => {}
+            Err(FollowError::Unavailable(e)) => println!("Unable to follow {}: {}", url, e),
+            Err(FollowError::Stopped(e)) => {
+                println!("Stopped following {}: {}", url, e);
+                return;
+            }
+        }
+        thread::sleep(Duration::from_secs(FOLLOW_INTERVAL_SECS));
+    }
+}
+
+fn get(url: &str, token: &str) -> Result<String, FollowError> {
+    let response = minreq::get(url)
+        .with_header("Authorization", format!("Bearer {}", token))
+        .with_timeout(FOLLOW_TIMEOUT_SECS)
+        .send();
+    let response = match response {
+        Ok(r) => r,
+        Err(e) => return Err(FollowError::Unavailable(e.to_string())),
+    };
+    let body = response.as_str().unwrap_or("").to_string();
+    if response.status_code != 200 {
+        return Err(FollowError::Unavailable(format!("status {} from {}: {}", response.status_code, url, body)));
+    }
+    Ok(body)
+}
+
+// Copies new entries from the primary, checking each follows the end of the
+// local log. Returns the number copied.
+fn sync(store: &KeyStore, url: &str, token: &str) -> Result<usize, FollowError> {
+    let local = match store.log_head() {
+        Ok(h) => h,
+        Err(e) => return Err(FollowError::Stopped(e)),
+    };
+    let body = get(&format!("{}/api/v1/log/head", url), token)?;
+    let remote: LogHead = match serde_json::from_str(&body) {
+        Ok(h) => h,
+        Err(e) => return Err(FollowError::Unavailable(format!("invalid log head: {}", e))),
+    };
+    check_heads(&local, &remote)?;
+    if remote.entries == local.entries {
+        return Ok(0);
+    }
+    let body = get(&format!("{}/admin/v1/log?from={}&limit={}", url, local.entries, MAX_LOG_ENTRIES), token)?;
+    let mut copied = 0;
+    for line in body.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: LogEntry = match serde_json::from_str(line) {
+            Ok(e) => e,
+            Err(e) => return Err(FollowError::Stopped(format!("invalid log entry: {}", e))),
+        };
+        if let Err(e) = store.apply_log_entry(&entry) {
+            return Err(FollowError::Stopped(e));
+        }
+        copied += 1;
+    }
+    if copied > 0 {
+        println!("Copied {} log entries from {}", copied, url);
+    }
+    Ok(copied)
+}
+
+// The primary's log can only grow. A shorter log, or a different hash for the
+// same length, means entries this server already copied have been changed.
+fn check_heads(local: &LogHead, remote: &LogHead) -> Result<(), FollowError> {
+    if remote.entries < local.entries {
+        let msg = format!("the primary has {} log entries but {} were copied", remote.entries, local.entries);
+        return Err(FollowError::Stopped(msg));
+    }
+    if remote.entries == local.entries && remote.hash != local.hash {
+        return Err(FollowError::Stopped("the primary's log has changed".to_string()));
+    }
+    Ok(())
+}
+
+pub fn tests() {
+    let head = |entries: u64, hash: &str| LogHead {
+        entries,
+        hash: hash.to_string(),
+    };
+    // heads that can follow
+    {
+        assert!(check_heads(&head(2, "aa"), &head(2, "aa")).is_ok(), "Same head did not match");
+        assert!(check_heads(&head(2, "aa"), &head(5, "bb")).is_ok(), "Longer primary log did not match");
+        assert!(check_heads(&LogHead::default(), &head(1, "aa")).is_ok(), "Empty log can't follow");
+    }
+    // heads that show the primary changed
+    {
+        assert!(check_heads(&head(2, "aa"), &head(1, "aa")).is_err(), "Shorter primary log matched");
+        assert!(check_heads(&head(2, "aa"), &head(2, "bb")).is_err(), "Changed primary log matched");
+    }
+}
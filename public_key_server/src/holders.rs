@@ -0,0 +1,227 @@
+use distribution_core::{parse_balances, OMaidBalance};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+// The MAID holder snapshot, in the json format used by distribute, either a
+// list of balances or a snapshot.json saved by distribute.
+// Without this every valid pair is accepted.
+const SNAPSHOT_FILE_ENV: &str = "SNAPSHOT_FILE";
+
+// What to do with submissions for addresses without a MAID balance,
+// reject (the default) or flag
+const SNAPSHOT_POLICY_ENV: &str = "SNAPSHOT_POLICY";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    // not saved
+    Reject,
+    // saved but marked as flagged
+    Flag,
+}
+
+// Whether an address will receive a distribution.
+// eligible is None when there's no snapshot to check against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Eligibility {
+    pub eligible: Option<bool>,
+    pub balance: Option<String>,
+}
+
+impl Eligibility {
+    pub fn describe(&self) -> String {
+        match (self.eligible, &self.balance) {
+            (None, _) => "unknown".to_string(),
+            (Some(true), Some(b)) => format!("yes, balance {}", b),
+            (Some(_), Some(b)) => format!("no, balance {}", b),
+            (Some(_), None) => "no, not in snapshot".to_string(),
+        }
+    }
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    balances: HashMap<String, OMaidBalance>,
+}
+
+// Balances by address from the snapshot file.
+// The file is reloaded when it changes, so a new snapshot can be put in place
+// without restarting the server.
+#[derive(Clone)]
+pub struct Holders {
+    path: Option<PathBuf>,
+    policy: Policy,
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+fn load(path: &PathBuf) -> Result<Loaded, String> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let json = match fs::read_to_string(path) {
+        Ok(j) => j,
+        Err(e) => return Err(format!("Error reading snapshot {}: {}", path.display(), e)),
+    };
+    let balances = match parse_balances(&json) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Error reading snapshot {}: {}", path.display(), e)),
+    };
+    Ok(Loaded {
+        modified,
+        balances: balances.into_iter().map(|b| (b.address.clone(), b)).collect(),
+    })
+}
+
+impl Holders {
+    pub fn from_env() -> Result<Holders, String> {
+        let policy = match std::env::var(SNAPSHOT_POLICY_ENV).unwrap_or_default().trim() {
+            "" | "reject" => Policy::Reject,
+            "flag" => Policy::Flag,
+            p => return Err(format!("{} must be reject or flag, not {}", SNAPSHOT_POLICY_ENV, p)),
+        };
+        match std::env::var(SNAPSHOT_FILE_ENV) {
+            Ok(path) if !path.trim().is_empty() => Holders::open(PathBuf::from(path.trim()), policy),
+            _ => Ok(Holders::none()),
+        }
+    }
+
+    pub fn open(path: PathBuf, policy: Policy) -> Result<Holders, String> {
+        let loaded = load(&path)?;
+        Ok(Holders {
+            path: Some(path),
+            policy,
+            loaded: Arc::new(RwLock::new(loaded)),
+        })
+    }
+
+    // No snapshot, every address is accepted
+    pub fn none() -> Holders {
+        Holders {
+            path: None,
+            policy: Policy::Flag,
+            loaded: Arc::new(RwLock::new(Loaded {
+                modified: None,
+                balances: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.loaded.read().unwrap().balances.len()
+    }
+
+    // Reloads the snapshot if the file has changed since it was loaded.
+    // Returns the number of holders if it was reloaded.
+    // If the new file can't be read the old snapshot is kept.
+    fn reload_if_changed(&self) -> Result<Option<usize>, String> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_none() || modified == self.loaded.read().unwrap().modified {
+            return Ok(None);
+        }
+        let mut loaded = self.loaded.write().unwrap();
+        // another request may have reloaded it while waiting for the lock
+        if modified == loaded.modified {
+            return Ok(None);
+        }
+        match load(path) {
+            Ok(l) => {
+                *loaded = l;
+                Ok(Some(loaded.balances.len()))
+            }
+            Err(e) => {
+                // don't try again until the file changes again
+                loaded.modified = modified;
+                Err(format!("{}, keeping the previous snapshot", e))
+            }
+        }
+    }
+
+    pub fn eligibility(&self, address: &str) -> Eligibility {
+        if self.path.is_none() {
+            return Eligibility {
+                eligible: None,
+                balance: None,
+            };
+        }
+        match self.reload_if_changed() {
+            Ok(Some(n)) => println!("Reloaded snapshot with {} holders", n),
+            Ok(None) => {}
+            Err(e) => println!("{}", e),
+        }
+        match self.loaded.read().unwrap().balances.get(address) {
+            Some(b) => Eligibility {
+                eligible: Some(b.is_eligible()),
+                balance: Some(b.balance.clone()),
+            },
+            None => Eligibility {
+                eligible: Some(false),
+                balance: None,
+            },
+        }
+    }
+
+    // Whether a submission for the address should be rejected
+    pub fn rejects(&self, eligibility: &Eligibility) -> bool {
+        self.policy == Policy::Reject && eligibility.eligible == Some(false)
+    }
+}
+
+pub fn tests() {
+    let path = std::env::temp_dir().join(format!("public_key_server_snapshot_{}.json", std::process::id()));
+    let holder = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    let empty = "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm";
+    let other = "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN";
+    let write = |json: &str| fs::write(&path, json).unwrap();
+    write(r#"[{"address":"1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH","balance":"10","reserved":"0"},{"address":"1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm","balance":"0","reserved":"0"}]"#);
+    let holders = Holders::open(path.clone(), Policy::Reject).unwrap();
+    // eligibility from the snapshot
+    {
+        let e = holders.eligibility(holder);
+        assert!(e.eligible == Some(true) && e.balance.as_deref() == Some("10"), "Holder eligibility is {:?}", e);
+        assert!(!holders.rejects(&e), "Holder was rejected");
+        let e = holders.eligibility(empty);
+        assert!(e.eligible == Some(false), "Zero balance eligibility is {:?}", e);
+        assert!(holders.rejects(&e), "Zero balance was not rejected");
+        let e = holders.eligibility(other);
+        assert!(e.eligible == Some(false) && e.balance.is_none(), "Unknown address eligibility is {:?}", e);
+    }
+    // flagged submissions are not rejected
+    {
+        let flag = Holders::open(path.clone(), Policy::Flag).unwrap();
+        let e = flag.eligibility(other);
+        assert!(!flag.rejects(&e), "Flag policy rejected address");
+    }
+    // changed file is reloaded, unreadable file keeps the old snapshot
+    {
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        write(r#"[{"address":"3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN","balance":"5","reserved":"0"}]"#);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        let reloaded = holders.reload_if_changed();
+        assert!(reloaded == Ok(Some(1)), "Changed snapshot reload gave {:?}", reloaded);
+        let e = holders.eligibility(other);
+        assert!(e.eligible == Some(true), "Changed snapshot was not reloaded");
+        write("not json");
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later + std::time::Duration::from_secs(10)).unwrap();
+        assert!(holders.reload_if_changed().is_err(), "Invalid snapshot did not return error");
+        assert!(holders.reload_if_changed() == Ok(None), "Invalid snapshot was reloaded again");
+        let e = holders.eligibility(other);
+        assert!(e.eligible == Some(true), "Invalid snapshot replaced the old one");
+    }
+    // no snapshot accepts everything
+    {
+        let none = Holders::none();
+        let e = none.eligibility(other);
+        assert!(e.eligible.is_none() && !none.rejects(&e), "No snapshot gave {:?}", e);
+        assert!(e.describe() == "unknown", "No snapshot is described as {}", e.describe());
+    }
+    let _ = fs::remove_file(&path);
+    assert!(Holders::open(path, Policy::Reject).is_err(), "Missing snapshot did not return error");
+}
@@ -0,0 +1,206 @@
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert, ServerConfig};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+// Where the server listens. The default only accepts connections from the
+// same machine, eg from a reverse proxy.
+const BIND_ADDRESS_ENV: &str = "BIND_ADDRESS";
+const PORT_ENV: &str = "PORT";
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+
+// PEM files for serving https without a reverse proxy, both must be set.
+// The certificate file is the full chain, eg fullchain.pem from certbot.
+const TLS_CERT_FILE_ENV: &str = "TLS_CERT_FILE";
+const TLS_KEY_FILE_ENV: &str = "TLS_KEY_FILE";
+
+#[derive(Debug, PartialEq)]
+pub struct Listen {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsFiles>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+fn env_value(name: &str) -> Option<String> {
+    match std::env::var(name) {
+        Ok(v) if !v.trim().is_empty() => Some(v.trim().to_string()),
+        _ => None,
+    }
+}
+
+pub fn from_env() -> Result<Listen, String> {
+    parse(
+        env_value(BIND_ADDRESS_ENV).as_deref(),
+        env_value(PORT_ENV).as_deref(),
+        env_value(TLS_CERT_FILE_ENV).as_deref(),
+        env_value(TLS_KEY_FILE_ENV).as_deref(),
+    )
+}
+
+fn parse(bind: Option<&str>, port: Option<&str>, cert: Option<&str>, key: Option<&str>) -> Result<Listen, String> {
+    let ip = match IpAddr::from_str(bind.unwrap_or(DEFAULT_BIND_ADDRESS)) {
+        Ok(ip) => ip,
+        Err(_) => return Err(format!("{} must be an ip address", BIND_ADDRESS_ENV)),
+    };
+    let port = match port.map(|p| p.parse::<u16>()) {
+        Some(Ok(p)) if p > 0 => p,
+        None => DEFAULT_PORT,
+        _ => return Err(format!("{} must be a port number", PORT_ENV)),
+    };
+    let tls = match (cert, key) {
+        (Some(cert), Some(key)) => Some(TlsFiles {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        }),
+        (None, None) => None,
+        _ => return Err(format!("{} and {} must both be set for https", TLS_CERT_FILE_ENV, TLS_KEY_FILE_ENV)),
+    };
+    Ok(Listen {
+        addr: SocketAddr::new(ip, port),
+        tls,
+    })
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    match File::open(path) {
+        Ok(f) => Ok(BufReader::new(f)),
+        Err(e) => Err(format!("Error reading {}: {}", path.display(), e)),
+    }
+}
+
+// The certificate chain and the first private key in the key file, which can
+// be pkcs8, rsa or ec
+fn load_certified_key(files: &TlsFiles) -> Result<CertifiedKey, String> {
+    let certs = match rustls_pemfile::certs(&mut open(&files.cert)?) {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Error reading {}: {}", files.cert.display(), e)),
+    };
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", files.cert.display()));
+    }
+    let mut reader = open(&files.key)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(Item::PKCS8Key(k))) | Ok(Some(Item::RSAKey(k))) | Ok(Some(Item::ECKey(k))) => break k,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("No private key in {}", files.key.display())),
+            Err(e) => return Err(format!("Error reading {}: {}", files.key.display(), e)),
+        }
+    };
+    let signing_key = match sign::any_supported_type(&PrivateKey(key)) {
+        Ok(k) => k,
+        Err(_) => return Err(format!("Unsupported private key in {}", files.key.display())),
+    };
+    let certs = certs.into_iter().map(Certificate).collect();
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+// Gives every connection the certificate last loaded from the files, so a
+// renewed certificate can be loaded without restarting the server
+pub struct CertResolver {
+    files: TlsFiles,
+    key: RwLock<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn load(files: &TlsFiles) -> Result<Arc<CertResolver>, String> {
+        let key = load_certified_key(files)?;
+        Ok(Arc::new(CertResolver {
+            files: files.clone(),
+            key: RwLock::new(key),
+        }))
+    }
+
+    // If the files can't be loaded the previous certificate is kept
+    pub fn reload(&self) -> Result<(), String> {
+        let key = load_certified_key(&self.files)?;
+        *self.key.write().unwrap() = key;
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.clone();
+        config
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+// Reloads the certificate when the process gets SIGHUP, eg from a certbot
+// deploy hook running `pkill -HUP public_key_server`
+#[cfg(unix)]
+pub fn reload_on_sighup(resolver: Arc<CertResolver>) -> Result<(), String> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error handling SIGHUP: {}", e)),
+    };
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            match resolver.reload() {
+                Ok(()) => println!("Reloaded certificate {}", resolver.files.cert.display()),
+                Err(e) => println!("{}, still using the previous certificate", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_resolver: Arc<CertResolver>) -> Result<(), String> {
+    Ok(())
+}
+
+pub fn tests() {
+    // defaults
+    {
+        let listen = parse(None, None, None, None).unwrap();
+        assert!(listen.addr.to_string() == "127.0.0.1:8080" && listen.tls.is_none(), "Default listener is {:?}", listen);
+    }
+    // address, port and tls files
+    {
+        let listen = parse(Some("::"), Some("443"), Some("fullchain.pem"), Some("privkey.pem")).unwrap();
+        assert!(listen.addr.to_string() == "[::]:443", "Listener address is {}", listen.addr);
+        let tls = listen.tls.unwrap();
+        assert!(tls.cert == Path::new("fullchain.pem") && tls.key == Path::new("privkey.pem"), "Tls files are {:?}", tls);
+    }
+    // invalid settings
+    {
+        assert!(parse(Some("localhost"), None, None, None).is_err(), "Hostname as bind address did not return error");
+        assert!(parse(None, Some("0"), None, None).is_err(), "Port 0 did not return error");
+        assert!(parse(None, Some("65536"), None, None).is_err(), "Port out of range did not return error");
+        assert!(parse(None, None, Some("fullchain.pem"), None).is_err(), "Certificate without key did not return error");
+    }
+    // certificate files that can't be used
+    {
+        let dir = std::env::temp_dir().join(format!("public_key_server_tls_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = TlsFiles {
+            cert: dir.join("fullchain.pem"),
+            key: dir.join("privkey.pem"),
+        };
+        assert!(CertResolver::load(&files).is_err(), "Missing files did not return error");
+        std::fs::write(&files.cert, "not a certificate").unwrap();
+        std::fs::write(&files.key, "not a key").unwrap();
+        assert!(CertResolver::load(&files).is_err(), "Files without pem did not return error");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
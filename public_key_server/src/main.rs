@@ -1,12 +1,55 @@
-use std::fs;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+mod admin;
+mod api;
+mod bip322;
+mod challenge;
+mod follower;
+mod holders;
+mod listener;
+mod metrics;
+mod rate_limit;
+mod receipt_key;
+mod rounds;
+mod signed_message;
+mod store;
+mod submission_log;
+
+use admin::AdminAuth;
+use bitcoin::{Address, Network};
+use challenge::Challenges;
+use distribution_core::ValidationError;
+use holders::Holders;
+use listener::CertResolver;
+use metrics::Metrics;
+use rate_limit::RateLimit;
+use receipt_key::ReceiptKey;
+use rounds::Rounds;
+use std::net::IpAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use store::{KeyStore, SubmissionMethod};
 use tide::{Response, Request};
 use tide::prelude::*;
-use tide_governor::GovernorMiddleware;
+use tide_rustls::TlsListener;
 
+const DB_DIR: &str = "keys.db";
 const KEYS_DIR: &str = "keys";
+const RECEIPT_KEY_FILE: &str = "receipt_key";
+// Followers check the log every few seconds, and catching up can take many
+// requests
+const LOG_RATE_LIMIT: u32 = 120;
+// Monitoring checks health and scrapes metrics often
+const MONITORING_RATE_LIMIT: u32 = 120;
+
+#[derive(Clone)]
+struct State {
+    challenges: Arc<Mutex<Challenges>>,
+    store: KeyStore,
+    holders: Holders,
+    receipt_key: ReceiptKey,
+    rounds: Rounds,
+    metrics: Metrics,
+}
 
 #[derive(Deserialize)]
 struct AddressKey {
@@ -14,96 +57,382 @@ struct AddressKey {
     pkhex: String,
 }
 
-#[derive(Serialize)]
-struct ReqError {
-    error: String,
+#[derive(Deserialize)]
+struct ChallengeRequest {
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct AddressSignature {
+    address: String,
+    signature: String,
 }
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     tests();
-    let mut app = tide::new();
-    app.at("/submit")
-        .with(GovernorMiddleware::per_minute(5)?)
-        .get(submit);
+    let store = match KeyStore::open(DB_DIR) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    match store.quarantine_corrupt() {
+        Ok(moved) => {
+            for address in moved {
+                println!("Quarantined corrupt record for {}", address);
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    // refuse to add to a log that has been changed
+    match store.verify_log() {
+        Ok(head) => println!("Log has {} entries, the last hash is {}", head.entries, head.hash),
+        Err(e) => {
+            println!("The log does not verify: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let rounds = match Rounds::from_env() {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 {
+        run_command(&store, &rounds, &args[1..]);
+        return Ok(());
+    }
+    let holders = match Holders::from_env() {
+        Ok(h) => h,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = holders.path() {
+        println!("Loaded snapshot {} with {} holders", path.display(), holders.len());
+    }
+    let receipt_key = match ReceiptKey::load_or_create(Path::new(RECEIPT_KEY_FILE)) {
+        Ok(k) => k,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Receipts are signed by server key {}", receipt_key.public_key());
+    let follow_url = match follower::url_from_env() {
+        Ok(u) => u,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let state = State {
+        challenges: Arc::new(Mutex::new(Challenges::default())),
+        store,
+        holders,
+        receipt_key,
+        rounds,
+        metrics: Metrics::default(),
+    };
+    let proxies = match rate_limit::trusted_proxies_from_env() {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let admin_token = match admin::token_from_env() {
+        Ok(t) => t,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let listen = match listener::from_env() {
+        Ok(l) => l,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let cert_resolver = match &listen.tls {
+        Some(files) => match CertResolver::load(files) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // a follower only changes by copying the primary's log, so it has no
+    // routes that save or delete keys
+    let following = follow_url.is_some();
+    if let Some(url) = follow_url {
+        match &admin_token {
+            Some(token) => follower::start(state.store.clone(), url, token.clone()),
+            None => {
+                println!("Following a server needs its admin token in ADMIN_TOKEN");
+                std::process::exit(1);
+            }
+        }
+    }
+    let metrics = state.metrics.clone();
+    let mut app = tide::with_state(state);
+    app.with(metrics);
+    if !following {
+        app.at("/submit")
+            .with(route_rate_limit("submit", &proxies))
+            .get(submit);
+        app.at("/challenge")
+            .with(route_rate_limit("challenge", &proxies))
+            .get(challenge);
+        app.at("/submit_signed")
+            .with(route_rate_limit("submit_signed", &proxies))
+            .get(submit_signed);
+        app.at("/api/v1/keys")
+            .with(route_rate_limit("api_submit", &proxies))
+            .post(api::submit_key);
+        app.at("/api/v1/keys/batch")
+            .with(route_rate_limit("api_batch", &proxies))
+            .post(api::submit_batch);
+    }
+    app.at("/api/v1/keys/:address")
+        .with(route_rate_limit("api_lookup", &proxies))
+        .get(api::get_key);
+    app.at("/api/v1/receipt_key")
+        .with(route_rate_limit("api_receipt_key", &proxies))
+        .get(api::get_receipt_key);
+    app.at("/api/v1/log/head")
+        .with(route_rate_limit_of("log_head", LOG_RATE_LIMIT, &proxies))
+        .get(api::get_log_head);
+    if let Some(token) = admin_token {
+        let auth = AdminAuth::new(&token);
+        app.at("/admin/v1/export")
+            .with(route_rate_limit("admin", &proxies))
+            .with(auth.clone())
+            .get(admin::export);
+        app.at("/admin/v1/stats")
+            .with(route_rate_limit("admin", &proxies))
+            .with(auth.clone())
+            .get(admin::get_stats);
+        app.at("/admin/v1/log")
+            .with(route_rate_limit_of("log", LOG_RATE_LIMIT, &proxies))
+            .with(auth.clone())
+            .get(admin::get_log);
+        if !following {
+            app.at("/admin/v1/keys/import")
+                .with(route_rate_limit("admin", &proxies))
+                .with(auth.clone())
+                .post(admin::import_keys);
+            app.at("/admin/v1/keys/:address")
+                .with(route_rate_limit("admin", &proxies))
+                .with(auth)
+                .delete(admin::delete_key);
+        }
+    }
+    app.at("/healthz")
+        .with(route_rate_limit_of("healthz", MONITORING_RATE_LIMIT, &proxies))
+        .get(metrics::get_health);
+    app.at("/metrics")
+        .with(route_rate_limit_of("metrics", MONITORING_RATE_LIMIT, &proxies))
+        .get(metrics::get_metrics);
     app.at("/")
-        .with(GovernorMiddleware::per_minute(5)?)
+        .with(route_rate_limit("index", &proxies))
         .serve_file("index.html")?;
-    app.listen("127.0.0.1:8080").await?;
+    match cert_resolver {
+        Some(resolver) => {
+            if let Err(e) = listener::reload_on_sighup(resolver.clone()) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+            println!("Listening on https://{}", listen.addr);
+            let tls = TlsListener::build().addrs(listen.addr).config(resolver.server_config());
+            app.listen(tls).await?;
+        }
+        None => {
+            println!("Listening on http://{}", listen.addr);
+            app.listen(listen.addr).await?;
+        }
+    }
     Ok(())
 }
 
-async fn submit(req: Request<()>) -> tide::Result {
+// Requests per minute for each client, 5 unless set in the environment
+fn route_rate_limit(route: &str, proxies: &[IpAddr]) -> RateLimit {
+    route_rate_limit_of(route, 5, proxies)
+}
+
+fn route_rate_limit_of(route: &str, default: u32, proxies: &[IpAddr]) -> RateLimit {
+    let limit = rate_limit::route_limit_from_env(route, default).and_then(|n| RateLimit::per_minute(n, proxies));
+    match limit {
+        Ok(l) => l,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Commands for managing the store, run instead of the server
+fn run_command(store: &KeyStore, rounds: &Rounds, args: &[String]) {
+    if args[0] == "export-round" {
+        let round = args.get(1).and_then(|id| id.parse::<u32>().ok()).and_then(|id| rounds.get(id));
+        let round = match round {
+            Some(r) => r,
+            None => {
+                println!("Usage: public_key_server export-round <round id> [dir]");
+                println!("The round must be in the rounds file.");
+                std::process::exit(1);
+            }
+        };
+        let dir = Path::new(args.get(2).map(|d| d.as_str()).unwrap_or(KEYS_DIR));
+        match store.export_at(dir, round.closes) {
+            Ok(n) => println!("Exported {} keys at the close of round {} {} to {}", n, round.id, round.name, dir.display()),
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let dir = Path::new(args.get(1).map(|d| d.as_str()).unwrap_or(KEYS_DIR));
+    let result = match args[0].as_str() {
+        "export" => store.export(dir).map(|n| format!("Exported {} keys to {}", n, dir.display())),
+        "import" => store.import(dir).map(|(n, skipped)| {
+            for s in skipped {
+                println!("Not imported {}", s);
+            }
+            format!("Imported {} keys from {}", n, dir.display())
+        }),
+        _ => {
+            println!("Usage: public_key_server [export|import] [dir]");
+            println!("       public_key_server export-round <round id> [dir]");
+            println!("Without a command the server is started.");
+            println!("export writes each key to a file in dir named by the address, for distribute.");
+            println!("export-round writes the keys as they were when the round closed.");
+            println!("import loads keys from a directory in the same layout.");
+            println!("dir defaults to {}", KEYS_DIR);
+            std::process::exit(1);
+        }
+    };
+    match result {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn submit(req: Request<State>) -> tide::Result {
     let qs: AddressKey = req.query()?;
-    // validation
-    let btc_err = validate_bitcoin_pair(&qs);
-    if btc_err.len() > 0 {
+    match api::save_key(req.state(), &qs, SubmissionMethod::PublicKey) {
+        Ok(key) => Ok(key.text().into()),
+        Err(e) => Ok(e.text_response()),
+    }
+}
+
+// The message to sign with the address to prove it's controlled by the
+// submitter, for wallets that can sign messages but don't show public keys.
+async fn challenge(req: Request<State>) -> tide::Result {
+    let qs: ChallengeRequest = req.query()?;
+    let addr_err = validate_address(&qs.address);
+    if !addr_err.is_empty() {
         let mut res = Response::new(400);
-        res.set_body(btc_err);
+        res.set_body(addr_err);
         return Ok(res);
     }
-    // save this pair to file
-    let save_err = save_to_file(&qs);
-    if save_err.len() > 0 {
-        let mut res = Response::new(500);
-        res.set_body(save_err);
-        return Ok(res);
+    // no point signing for an address that will be rejected
+    if let Err(e) = submission_round(req.state(), store::now()) {
+        return Ok(forbidden(e));
+    }
+    let eligibility = req.state().holders.eligibility(&qs.address);
+    if req.state().holders.rejects(&eligibility) {
+        return Ok(not_eligible());
+    }
+    let message = req.state().challenges.lock().unwrap().issue(&qs.address);
+    match message {
+        Ok(m) => Ok(m.into()),
+        Err(e) => {
+            let mut res = Response::new(503);
+            res.set_body(e);
+            Ok(res)
+        }
     }
-    // response
-    Ok(format!("Success\nAddress: {}\nPublic Key: {}", qs.address, qs.pkhex).into())
 }
 
-fn key_filename(address: &str) -> PathBuf {
-    Path::new(KEYS_DIR).join(address)
+// Recovers the public key from a signature of the challenge for the address
+async fn submit_signed(req: Request<State>) -> tide::Result {
+    let qs: AddressSignature = req.query()?;
+    match api::save_signed_key(req.state(), &qs.address, &qs.signature) {
+        Ok(key) => Ok(key.text().into()),
+        Err(e) => Ok(e.text_response()),
+    }
 }
 
-fn save_to_file(ak: &AddressKey) -> &str {
-    // TODO consider if concurrent write is a problem here
-    // I don't think there's a practical concern but it may expose a crash
-    // TODO consider if the address can be manipulated into file system chaos
-    let _ = fs::create_dir(KEYS_DIR);
-    let filename = key_filename(&ak.address);
-    let file = fs::File::create(filename);
-    if !file.is_ok() {
-        return "Error creating record";
-    }
-    let err = file.unwrap().write_all(ak.pkhex.as_bytes());
-    if !err.is_ok() {
-        return "Error writing record";
-    }
-    return "";
+fn not_eligible() -> Response {
+    forbidden("Address has no MAID balance in the snapshot")
 }
 
-fn validate_bitcoin_pair(ak: &AddressKey) -> &str {
-    // bitcoin public key is valid
-    let pk = bitcoin::PublicKey::from_str(&ak.pkhex);
-    if !pk.is_ok() {
-        return "Invalid public key";
+fn forbidden(msg: &str) -> Response {
+    let mut res = Response::new(403);
+    res.set_body(msg);
+    res
+}
+
+// The round a submission at time is made in, None if rounds aren't used.
+// Submissions are rejected when rounds are used and none is open.
+fn submission_round(state: &State, time: u64) -> Result<Option<u32>, &'static str> {
+    if state.rounds.is_empty() {
+        return Ok(None);
     }
-    // bitcoin address is valid
-    let addr = bitcoin::Address::from_str(&ak.address);
-    if !addr.is_ok() {
-        return "Invalid address";
+    match state.rounds.open_at(time) {
+        Some(r) => Ok(Some(r.id)),
+        None => Err("No submission round is open"),
     }
-    let btc_addr = addr.clone().unwrap().require_network(bitcoin::Network::Bitcoin);
-    if !btc_addr.is_ok() {
+}
+
+fn validate_address(address: &str) -> &'static str {
+    let addr = match Address::from_str(address) {
+        Ok(a) => a,
+        Err(_) => return "Invalid address",
+    };
+    if addr.require_network(Network::Bitcoin).is_err() {
         return "Invalid network";
     }
-    // bitcoin public key matches bitcoin address
-    // p2pkh
-    if btc_addr.clone().unwrap().is_related_to_pubkey(&pk.clone().unwrap()) {
-        return "";
-    }
-    // p2wpkh
-    let p2wpkh_addr = bitcoin::Address::p2shwpkh(&pk.clone().unwrap(), bitcoin::Network::Bitcoin).unwrap();
-    if p2wpkh_addr == addr.unwrap() {
-        return "";
-    }
-    return "Public key does not match address";
+    ""
+}
+
+fn validate_bitcoin_pair(ak: &AddressKey) -> Result<(), ValidationError> {
+    distribution_core::validate_bitcoin_pair(&ak.address, &ak.pkhex)
 }
 
 // Sure it's not standard to test like this but it's ok
 fn tests() {
+    distribution_core::tests();
+    admin::tests();
+    api::tests();
+    challenge::tests();
+    follower::tests();
+    holders::tests();
+    listener::tests();
+    metrics::tests();
+    rate_limit::tests();
+    receipt_key::tests();
+    rounds::tests();
+    bip322::tests();
+    signed_message::tests();
+    store::tests();
+    submission_log::tests();
     // valid bitcoin pair
     {
         let ak = AddressKey {
@@ -111,7 +440,7 @@ fn tests() {
             pkhex: "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc".to_string(),
         };
         let err = validate_bitcoin_pair(&ak);
-        assert!(err == "", "Valid bitcoin pair threw error: {}", err);
+        assert!(err.is_ok(), "Valid bitcoin pair threw error: {:?}", err);
     }
     // invalid bitcoin pk (last char of pk changed)
     {
@@ -120,7 +449,7 @@ fn tests() {
             pkhex: "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadd".to_string(),
         };
         let err = validate_bitcoin_pair(&ak);
-        assert!(err != "", "Invalid bitcoin public key should give error but did not");
+        assert!(err.is_err(), "Invalid bitcoin public key should give error but did not");
     }
     // invalid bitcoin addr
     {
@@ -129,7 +458,7 @@ fn tests() {
             pkhex: "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc".to_string(),
         };
         let err = validate_bitcoin_pair(&ak);
-        assert!(err != "", "Invalid bitcoin address should give error but did not");
+        assert!(err.is_err(), "Invalid bitcoin address should give error but did not");
     }
     // mismatched bitcoin pair
     {
@@ -138,16 +467,25 @@ fn tests() {
             pkhex: "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc".to_string(),
         };
         let err = validate_bitcoin_pair(&ak);
-        assert!(err != "", "Mismatched bitcoin pair should give error but did not");
+        assert!(err.is_err(), "Mismatched bitcoin pair should give error but did not");
     }
-    // p2wpkh bitcoin pair
+    // p2sh-p2wpkh bitcoin pair
     {
         let ak = AddressKey {
             address: "39Q6Y89u1wMYacDJw63UNiYgj3wfJtZbRj".to_string(),
             pkhex: "03608934ee3cd78469528f55bab4f1db60f3fbdd793067503dfef6d7903dbf61e9".to_string(),
         };
         let err = validate_bitcoin_pair(&ak);
-        assert!(err == "", "Valid P2WPKH pair threw error: {}", err);
+        assert!(err.is_ok(), "Valid P2SH-P2WPKH pair threw error: {:?}", err);
+    }
+    // native segwit bitcoin pair
+    {
+        let ak = AddressKey {
+            address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+            pkhex: "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string(),
+        };
+        let err = validate_bitcoin_pair(&ak);
+        assert!(err.is_ok(), "Valid bech32 pair threw error: {:?}", err);
     }
     // junk input
     {
@@ -156,7 +494,7 @@ fn tests() {
             pkhex: "33".to_string(),
         };
         let btc_err = validate_bitcoin_pair(&ak);
-        assert!(btc_err != "", "Junk input did not return error");
+        assert!(btc_err.is_err(), "Junk input did not return error");
     }
     // address as filename can't break stuff
     {
@@ -165,7 +503,16 @@ fn tests() {
             pkhex: "027a41a6bef82652407562fdff7cbed487ea39e51e0010269cefcd103d421baadc".to_string(),
         };
         let btc_err = validate_bitcoin_pair(&ak);
-        assert!(btc_err != "", "Filename abuse did not return error");
+        assert!(btc_err.is_err(), "Filename abuse did not return error");
+    }
+    // challenges are only for valid mainnet addresses
+    {
+        let err = validate_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert!(err.is_empty(), "Valid address threw error: {}", err);
+        let err = validate_address("../../1CoT3ACy3L8MUSRcRbi9FuZ8Yckz3Ghpwz");
+        assert!(!err.is_empty(), "Filename abuse did not return error");
+        let err = validate_address("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx");
+        assert!(!err.is_empty(), "Testnet address did not return error");
     }
     // TODO
     // bitcoin pubkey mixed case
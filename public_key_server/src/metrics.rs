@@ -0,0 +1,127 @@
+use crate::api::{ErrorCode, SUBMISSION_ERRORS};
+use crate::store::KeyStore;
+use crate::State;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tide::{Middleware, Next, Request, Response};
+
+// Counts since the server started, served at /metrics
+#[derive(Clone)]
+pub struct Metrics {
+    accepted: Arc<AtomicU64>,
+    rejected: Arc<Mutex<BTreeMap<String, u64>>>,
+    rate_limited: Arc<AtomicU64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        // every reason is listed from 0 so there's a value before the first
+        // rejection
+        let reasons = SUBMISSION_ERRORS.iter().map(|c| (c.reason(), 0)).collect();
+        Metrics {
+            accepted: Arc::new(AtomicU64::new(0)),
+            rejected: Arc::new(Mutex::new(reasons)),
+            rate_limited: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, code: ErrorCode) {
+        *self.rejected.lock().unwrap().entry(code.reason()).or_insert(0) += 1;
+    }
+
+    // The prometheus text format
+    fn render(&self, store: &KeyStore) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+            let _ = writeln!(text, "# HELP public_key_server_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE public_key_server_{} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(text, "public_key_server_{}{} {}", name, labels, value);
+            }
+        };
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        metric("submissions_accepted_total", "counter", "Submissions saved", vec![(String::new(), accepted)]);
+        let rejected = self.rejected.lock().unwrap().iter()
+            .map(|(reason, n)| (format!("{{reason=\"{}\"}}", reason), *n))
+            .collect();
+        metric("submissions_rejected_total", "counter", "Submissions rejected, by reason", rejected);
+        let rate_limited = self.rate_limited.load(Ordering::Relaxed);
+        metric("rate_limited_total", "counter", "Requests rejected by the rate limit", vec![(String::new(), rate_limited)]);
+        let failures = store.write_failures();
+        metric("storage_write_failures_total", "counter", "Changes that could not be written to the database", vec![(String::new(), failures)]);
+        metric("keys", "gauge", "Addresses with a public key in the store", vec![(String::new(), store.len() as u64)]);
+        text
+    }
+}
+
+// Counts requests refused by any route's rate limit
+#[tide::utils::async_trait]
+impl<S: Clone + Send + Sync + 'static> Middleware<S> for Metrics {
+    async fn handle(&self, req: Request<S>, next: Next<'_, S>) -> tide::Result {
+        let res = next.run(req).await;
+        if res.status() == tide::StatusCode::TooManyRequests {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(res)
+    }
+}
+
+// GET /metrics
+pub async fn get_metrics(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let mut res = Response::new(200);
+    res.set_body(state.metrics.render(&state.store));
+    res.set_content_type("text/plain; version=0.0.4");
+    Ok(res)
+}
+
+// GET /healthz, ok if the store can be read
+pub async fn get_health(req: Request<State>) -> tide::Result {
+    let mut res = Response::new(200);
+    match req.state().store.log_head() {
+        Ok(_) => res.set_body("ok"),
+        Err(e) => {
+            res.set_status(503);
+            res.set_body(e);
+        }
+    }
+    Ok(res)
+}
+
+pub fn tests() {
+    // counts are rendered with every known reason
+    {
+        let metrics = Metrics::default();
+        let store = KeyStore::temporary();
+        metrics.accepted();
+        metrics.accepted();
+        metrics.rejected(ErrorCode::KeyMismatch);
+        metrics.rejected(ErrorCode::NotEligible);
+        metrics.rejected(ErrorCode::KeyMismatch);
+        metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+        let text = metrics.render(&store);
+        let lines = [
+            "# TYPE public_key_server_submissions_accepted_total counter",
+            "public_key_server_submissions_accepted_total 2",
+            "public_key_server_submissions_rejected_total{reason=\"key_mismatch\"} 2",
+            "public_key_server_submissions_rejected_total{reason=\"not_eligible\"} 1",
+            "public_key_server_submissions_rejected_total{reason=\"invalid_public_key\"} 0",
+            "public_key_server_submissions_rejected_total{reason=\"invalid_signature\"} 0",
+            "public_key_server_rate_limited_total 1",
+            "public_key_server_storage_write_failures_total 0",
+            "# TYPE public_key_server_keys gauge",
+            "public_key_server_keys 0",
+        ];
+        for line in lines {
+            assert!(text.lines().any(|l| l == line), "Metrics are missing {}:\n{}", line, text);
+        }
+    }
+}
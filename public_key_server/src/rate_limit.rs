@@ -0,0 +1,209 @@
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::api::{ErrorCode, ReqError};
+use tide::{Middleware, Next, Request, Response};
+
+// Proxies allowed to say which client a request is for, as a comma separated
+// list of ip addresses. Without this the forwarding headers are ignored.
+const TRUSTED_PROXIES_ENV: &str = "TRUSTED_PROXIES";
+
+// Per minute limit for a route is read from RATE_LIMIT_<ROUTE>, eg
+// RATE_LIMIT_SUBMIT_SIGNED=10
+const RATE_LIMIT_ENV_PREFIX: &str = "RATE_LIMIT_";
+
+pub fn trusted_proxies_from_env() -> Result<Vec<IpAddr>, String> {
+    let value = std::env::var(TRUSTED_PROXIES_ENV).unwrap_or_default();
+    let mut proxies = Vec::<IpAddr>::new();
+    for p in value.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        match IpAddr::from_str(p) {
+            Ok(ip) => proxies.push(ip),
+            Err(_) => return Err(format!("Invalid ip address in {}: {}", TRUSTED_PROXIES_ENV, p)),
+        }
+    }
+    Ok(proxies)
+}
+
+pub fn route_limit_from_env(route: &str, default: u32) -> Result<u32, String> {
+    let name = format!("{}{}", RATE_LIMIT_ENV_PREFIX, route.to_uppercase());
+    match std::env::var(&name) {
+        Ok(v) => match v.trim().parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("{} must be a number of requests per minute more than 0", name)),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+// The address a forwarding header gives for a hop, from either
+// X-Forwarded-For (1.2.3.4) or Forwarded for= (1.2.3.4:5678, "[::1]:5678").
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = IpAddr::from_str(hop) {
+        return Some(ip);
+    }
+    if let Ok(addr) = SocketAddr::from_str(hop) {
+        return Some(addr.ip());
+    }
+    // bracketed ipv6 without a port
+    IpAddr::from_str(hop.trim_start_matches('[').trim_end_matches(']')).ok()
+}
+
+// The hops in a Forwarded header, in order from the client to the last proxy
+fn forwarded_hops(forwarded: &str) -> Vec<&str> {
+    let mut hops = Vec::<&str>::new();
+    for element in forwarded.split(',') {
+        for pair in element.split(';') {
+            let mut kv = pair.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            if key.eq_ignore_ascii_case("for") {
+                hops.push(kv.next().unwrap_or(""));
+            }
+        }
+    }
+    hops
+}
+
+// The client ip for a request.
+// Requests from a trusted proxy are attributed to the nearest address in the
+// forwarding chain that isn't a trusted proxy. Addresses further along the
+// chain were added by the client or untrusted proxies so can't be believed.
+// If a hop can't be read the request is attributed to the proxy that
+// added it, so clients can't avoid the limit by sending junk.
+pub fn client_ip(peer: IpAddr, forwarded: Option<&str>, x_forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let hops = match (forwarded, x_forwarded_for) {
+        (Some(f), _) => forwarded_hops(f),
+        (None, Some(x)) => x.split(',').collect(),
+        (None, None) => return peer,
+    };
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        let ip = match parse_hop(hop) {
+            Some(ip) => ip,
+            None => return client,
+        };
+        client = ip;
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+// Limits each client to a number of requests per minute on a route.
+// Each route has its own limiter so limits can be set per route.
+#[derive(Clone)]
+pub struct RateLimit {
+    limiter: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl RateLimit {
+    pub fn per_minute(times: u32, trusted_proxies: &[IpAddr]) -> Result<RateLimit, String> {
+        let times = match NonZeroU32::new(times) {
+            Some(t) => t,
+            None => return Err("Rate limit must be more than 0".to_string()),
+        };
+        Ok(RateLimit {
+            limiter: Arc::new(RateLimiter::keyed(Quota::per_minute(times))),
+            trusted_proxies: Arc::new(trusted_proxies.to_vec()),
+        })
+    }
+
+    fn client_ip<State>(&self, req: &Request<State>) -> Option<IpAddr> {
+        let peer = req.peer_addr().and_then(|a| SocketAddr::from_str(a).ok())?.ip();
+        // repeated headers are the same as one header with the values joined
+        let header = |name: &str| {
+            req.header(name).map(|values| values.iter().map(|v| v.as_str()).collect::<Vec<&str>>().join(","))
+        };
+        let forwarded = header("Forwarded");
+        let x_forwarded_for = header("X-Forwarded-For");
+        Some(client_ip(peer, forwarded.as_deref(), x_forwarded_for.as_deref(), &self.trusted_proxies))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let ip = match self.client_ip(&req) {
+            Some(ip) => ip,
+            None => {
+                let mut res = Response::new(500);
+                res.set_body("Unknown client address");
+                return Ok(res);
+            }
+        };
+        if let Err(not_until) = self.limiter.check_key(&ip) {
+            let wait = not_until.wait_time_from(DefaultClock::default().now());
+            // same format as api errors so clients can handle it
+            let mut res = ReqError::new(ErrorCode::RateLimited, "Too many requests, try again later").response();
+            res.insert_header("Retry-After", wait.as_secs().max(1).to_string());
+            return Ok(res);
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+pub fn tests() {
+    let ip = |s: &str| IpAddr::from_str(s).unwrap();
+    let proxy = ip("127.0.0.1");
+    let trusted = [proxy, ip("10.0.0.2")];
+    let client = ip("203.0.113.7");
+    // direct requests use the peer even if they say they're forwarded
+    {
+        let r = client_ip(client, None, Some("198.51.100.1"), &trusted);
+        assert!(r == client, "Untrusted peer was able to set client ip to {}", r);
+    }
+    // request from a trusted proxy without headers is the proxy
+    {
+        let r = client_ip(proxy, None, None, &trusted);
+        assert!(r == proxy, "Proxy without headers gave {}", r);
+    }
+    // X-Forwarded-For from a trusted proxy
+    {
+        let r = client_ip(proxy, None, Some("203.0.113.7"), &trusted);
+        assert!(r == client, "X-Forwarded-For gave {}", r);
+    }
+    // spoofed addresses before the real client are ignored
+    {
+        let r = client_ip(proxy, None, Some("198.51.100.1, 203.0.113.7"), &trusted);
+        assert!(r == client, "Spoofed X-Forwarded-For gave {}", r);
+    }
+    // chain of trusted proxies
+    {
+        let r = client_ip(proxy, None, Some("203.0.113.7, 10.0.0.2"), &trusted);
+        assert!(r == client, "Chain of trusted proxies gave {}", r);
+    }
+    // Forwarded is preferred and can include ports and ipv6
+    {
+        let r = client_ip(proxy, Some("for=198.51.100.1;proto=https, for=\"203.0.113.7:4711\""), Some("192.0.2.1"), &trusted);
+        assert!(r == client, "Forwarded gave {}", r);
+        let r = client_ip(proxy, Some("For=\"[2001:db8:cafe::17]:4711\""), None, &trusted);
+        assert!(r == ip("2001:db8:cafe::17"), "Forwarded ipv6 gave {}", r);
+    }
+    // junk hop is attributed to the proxy that added it
+    {
+        let r = client_ip(proxy, None, Some("203.0.113.7, junk"), &trusted);
+        assert!(r == proxy, "Junk X-Forwarded-For gave {}", r);
+        let r = client_ip(proxy, Some("for=unknown"), None, &trusted);
+        assert!(r == proxy, "Obfuscated Forwarded gave {}", r);
+    }
+    // limits are per client
+    {
+        let limit = RateLimit::per_minute(2, &trusted).unwrap();
+        assert!(limit.limiter.check_key(&client).is_ok(), "First request was limited");
+        assert!(limit.limiter.check_key(&client).is_ok(), "Second request was limited");
+        assert!(limit.limiter.check_key(&client).is_err(), "Third request was not limited");
+        assert!(limit.limiter.check_key(&ip("203.0.113.8")).is_ok(), "Other client was limited");
+    }
+    // zero limit
+    {
+        assert!(RateLimit::per_minute(0, &trusted).is_err(), "Zero rate limit did not return error");
+    }
+}
@@ -0,0 +1,77 @@
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use distribution_core::{write_private, Receipt};
+use std::fs;
+use std::path::Path;
+
+// The key receipts are signed with.
+// Created the first time the server starts. It must be kept and backed up,
+// receipts can only be checked against the public key for it.
+#[derive(Clone, Copy)]
+pub struct ReceiptKey {
+    sk: SecretKey,
+}
+
+fn new_secret_key() -> SecretKey {
+    loop {
+        // almost every 32 bytes is a valid key
+        if let Ok(sk) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+            return sk;
+        }
+    }
+}
+
+impl ReceiptKey {
+    pub fn load_or_create(path: &Path) -> Result<ReceiptKey, String> {
+        if path.exists() {
+            let skhex = match fs::read_to_string(path) {
+                Ok(s) => s,
+                Err(e) => return Err(format!("Error reading receipt key {}: {}", path.display(), e)),
+            };
+            let bytes = hex::decode(skhex.trim()).unwrap_or_default();
+            return match SecretKey::from_slice(&bytes) {
+                Ok(sk) => Ok(ReceiptKey { sk }),
+                Err(_) => Err(format!("Invalid receipt key in {}", path.display())),
+            };
+        }
+        let sk = new_secret_key();
+        // only the server needs to read it
+        if let Err(e) = write_private(path, hex::encode(sk.secret_bytes()).as_bytes()) {
+            return Err(format!("Error saving receipt key {}: {}", path.display(), e));
+        }
+        Ok(ReceiptKey { sk })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.sk.public_key(&Secp256k1::new())
+    }
+
+    pub fn sign(&self, address: &str, pkhex: &str, timestamp: u64) -> Receipt {
+        Receipt::sign(address, pkhex, timestamp, &self.sk)
+    }
+}
+
+pub fn tests() {
+    let path = std::env::temp_dir().join(format!("public_key_server_receipt_key_{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    // created once then loaded
+    {
+        let created = ReceiptKey::load_or_create(&path).unwrap();
+        let loaded = ReceiptKey::load_or_create(&path).unwrap();
+        assert!(created.public_key() == loaded.public_key(), "Loaded receipt key is different to the created key");
+        let receipt = loaded.sign("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH", "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", 1000);
+        let r = receipt.verify(&created.public_key().to_string());
+        assert!(r.is_ok(), "Receipt did not verify with the server key: {:?}", r);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+            assert!(mode == 0o600, "Receipt key has mode {:o}", mode);
+        }
+    }
+    // invalid key file is an error rather than being replaced
+    {
+        fs::write(&path, "not a key").unwrap();
+        assert!(ReceiptKey::load_or_create(&path).is_err(), "Invalid receipt key did not return error");
+    }
+    let _ = fs::remove_file(&path);
+}
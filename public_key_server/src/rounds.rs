@@ -0,0 +1,108 @@
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+
+// A json file listing the submission rounds, eg
+// [{"id": 1, "name": "testnet", "opens": 1700000000, "closes": 1701000000}]
+// Without this submissions are always open and aren't tagged with a round.
+const ROUNDS_FILE_ENV: &str = "ROUNDS_FILE";
+
+// A period when submissions are accepted, ending in a distribution.
+// Times are unix seconds, a submission at closes is too late.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Round {
+    pub id: u32,
+    pub name: String,
+    pub opens: u64,
+    pub closes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Rounds {
+    rounds: Arc<Vec<Round>>,
+}
+
+impl Rounds {
+    pub fn from_env() -> Result<Rounds, String> {
+        let path = match std::env::var(ROUNDS_FILE_ENV) {
+            Ok(p) if !p.trim().is_empty() => p.trim().to_string(),
+            _ => return Ok(Rounds::default()),
+        };
+        let json = match fs::read_to_string(&path) {
+            Ok(j) => j,
+            Err(e) => return Err(format!("Error reading rounds {}: {}", path, e)),
+        };
+        match Rounds::parse(&json) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(format!("Error in rounds {}: {}", path, e)),
+        }
+    }
+
+    // Rounds must have unique ids, close after they open and not overlap, so
+    // every submission belongs to at most one round.
+    pub fn parse(json: &str) -> Result<Rounds, String> {
+        let mut rounds: Vec<Round> = match serde_json::from_str(json) {
+            Ok(r) => r,
+            Err(e) => return Err(e.to_string()),
+        };
+        rounds.sort_by_key(|r| r.opens);
+        for (i, r) in rounds.iter().enumerate() {
+            if r.closes <= r.opens {
+                return Err(format!("Round {} closes before it opens", r.id));
+            }
+            if rounds.iter().filter(|o| o.id == r.id).count() > 1 {
+                return Err(format!("Round {} is listed more than once", r.id));
+            }
+            if i > 0 && rounds[i - 1].closes > r.opens {
+                return Err(format!("Round {} overlaps round {}", rounds[i - 1].id, r.id));
+            }
+        }
+        Ok(Rounds {
+            rounds: Arc::new(rounds),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rounds.is_empty()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Round> {
+        self.rounds.iter().find(|r| r.id == id)
+    }
+
+    // The round accepting submissions at a time
+    pub fn open_at(&self, time: u64) -> Option<&Round> {
+        self.rounds.iter().find(|r| r.opens <= time && time < r.closes)
+    }
+}
+
+pub fn tests() {
+    let json = r#"[
+        {"id": 2, "name": "mainnet", "opens": 3000, "closes": 4000},
+        {"id": 1, "name": "testnet", "opens": 1000, "closes": 2000}
+    ]"#;
+    let rounds = Rounds::parse(json).unwrap();
+    // open round by time
+    {
+        assert!(rounds.open_at(999).is_none(), "Round open before the first opens");
+        assert!(rounds.open_at(1000).map(|r| r.id) == Some(1), "First round not open when it opens");
+        assert!(rounds.open_at(2000).is_none(), "Round open at its close");
+        assert!(rounds.open_at(3500).map(|r| r.id) == Some(2), "Second round not open");
+        assert!(rounds.open_at(5000).is_none(), "Round open after the last closes");
+        assert!(rounds.get(2).map(|r| r.closes) == Some(4000), "Round by id is {:?}", rounds.get(2));
+    }
+    // no rounds
+    {
+        let none = Rounds::default();
+        assert!(none.is_empty() && none.open_at(1000).is_none(), "Default rounds are not empty");
+    }
+    // invalid rounds
+    {
+        let backwards = r#"[{"id": 1, "name": "a", "opens": 2000, "closes": 1000}]"#;
+        assert!(Rounds::parse(backwards).is_err(), "Round closing before it opens did not return error");
+        let overlap = r#"[{"id": 1, "name": "a", "opens": 1000, "closes": 3000}, {"id": 2, "name": "b", "opens": 2000, "closes": 4000}]"#;
+        assert!(Rounds::parse(overlap).is_err(), "Overlapping rounds did not return error");
+        let repeated = r#"[{"id": 1, "name": "a", "opens": 1000, "closes": 2000}, {"id": 1, "name": "b", "opens": 3000, "closes": 4000}]"#;
+        assert!(Rounds::parse(repeated).is_err(), "Repeated round id did not return error");
+    }
+}
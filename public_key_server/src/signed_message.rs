@@ -0,0 +1,113 @@
+use crate::bip322;
+use crate::store::SubmissionMethod;
+use bitcoin::base64::prelude::{Engine as _, BASE64_STANDARD};
+use bitcoin::hashes::Hash;
+use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+use bitcoin::PublicKey;
+use secp256k1::Secp256k1;
+use std::str::FromStr;
+
+// Recovers the public key that signed the message with bitcoin signmessage.
+// The first byte of the signature says how to recover the key:
+// 27-30 uncompressed p2pkh, 31-34 compressed p2pkh (bitcoin core, electrum)
+// 35-38 p2sh-p2wpkh, 39-42 p2wpkh (BIP137, trezor and others)
+// Segwit keys are always compressed. The header doesn't prove which address
+// was signed for, so the caller must still check the key matches the address.
+pub fn recover_public_key(message: &str, signature_base64: &str) -> Result<PublicKey, &'static str> {
+    let mut sig = match BASE64_STANDARD.decode(signature_base64.trim()) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid signature encoding"),
+    };
+    if sig.len() != 65 {
+        return Err("Invalid signature length");
+    }
+    // BIP137 segwit headers become the matching compressed p2pkh header
+    if (35..=42).contains(&sig[0]) {
+        sig[0] = 31 + (sig[0] - 35) % 4;
+    }
+    if !(27..=34).contains(&sig[0]) {
+        return Err("Invalid signature header");
+    }
+    let signature = match MessageSignature::from_slice(&sig) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid signature"),
+    };
+    match signature.recover_pubkey(&Secp256k1::verification_only(), signed_msg_hash(message)) {
+        Ok(pk) => Ok(pk),
+        Err(_) => Err("Invalid signature"),
+    }
+}
+
+// The public key that signed the message for the address, from a legacy
+// signmessage signature or a BIP322 simple or full signature.
+pub fn public_key_from_signature(address: &str, message: &str, signature_base64: &str) -> Result<(PublicKey, SubmissionMethod), &'static str> {
+    let sig = match BASE64_STANDARD.decode(signature_base64.trim()) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid signature encoding"),
+    };
+    // legacy signatures are always 65 bytes, BIP322 witnesses are longer
+    if sig.len() == 65 {
+        return recover_public_key(message, signature_base64).map(|pk| (pk, SubmissionMethod::SignMessage));
+    }
+    bip322::verify(address, message, &sig).map(|pk| (pk, SubmissionMethod::Bip322))
+}
+
+pub fn tests() {
+    // signature from the rust-bitcoin tests
+    let message = "rust-bitcoin MessageSignature test";
+    let signature = "IAM2qX24tYx/bdBTIgVLhD8QEAjrPlJpmjB4nZHdRYGIBa4DmVulAcwjPnWe6Q5iEwXH6F0pUCJP/ZeHPWS1h1o=";
+    let pkhex = "0351537cc127b4fa40b72dea904a34ab60dcd45132708d746b78e6c04172f90afa";
+    // known signature
+    {
+        let pk = recover_public_key(message, signature);
+        assert!(pk.map(|pk| pk.to_string()) == Ok(pkhex.to_string()), "Known signature recovered the wrong key");
+    }
+    // different message recovers a different key
+    {
+        let pk = recover_public_key("a different message", signature);
+        assert!(pk.map(|pk| pk.to_string()) != Ok(pkhex.to_string()), "Signature recovered the key for a different message");
+    }
+    // signatures for each type of address, secret key 1
+    {
+        let secp = Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&[[0; 31].as_slice(), &[1]].concat()).unwrap();
+        let message = "MAID distribution signed message test";
+        let msg = secp256k1::Message::from_digest(signed_msg_hash(message).to_byte_array());
+        let (recid, compact) = secp.sign_ecdsa_recoverable(&msg, &sk).serialize_compact();
+        let cases = [
+            // uncompressed p2pkh
+            (27, false, "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm"),
+            // compressed p2pkh
+            (31, true, "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"),
+            // BIP137 p2sh-p2wpkh
+            (35, true, "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN"),
+            // BIP137 p2wpkh
+            (39, true, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+        ];
+        for (header, compressed, address) in cases {
+            let mut sig = vec![header + recid.to_i32() as u8];
+            sig.extend_from_slice(&compact);
+            let pk = recover_public_key(message, &BASE64_STANDARD.encode(&sig)).unwrap();
+            assert!(pk.compressed == compressed, "Header {} recovered the wrong key type", header);
+            let r = distribution_core::validate_bitcoin_pair(address, &pk.to_string());
+            assert!(r.is_ok(), "Signature for {} recovered a key for a different address: {:?}", address, r);
+        }
+    }
+    // either format can be used
+    {
+        let pk = public_key_from_signature("1DUm3t5rF5yMD6r1VbCmgbPDe6LAVYVGrQ", message, signature);
+        assert!(pk == Ok((PublicKey::from_str(pkhex).unwrap(), SubmissionMethod::SignMessage)), "Legacy signature not recognised");
+        let bip322_sig = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        let pk = public_key_from_signature("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l", "Hello World", bip322_sig);
+        assert!(pk.map(|pk| pk.1) == Ok(SubmissionMethod::Bip322), "BIP322 signature not recognised");
+    }
+    // junk
+    {
+        let r = recover_public_key(message, "not base64!");
+        assert!(r.is_err(), "Junk signature did not return error");
+        let r = recover_public_key(message, &BASE64_STANDARD.encode([31; 10]));
+        assert!(r.is_err(), "Short signature did not return error");
+        let r = recover_public_key(message, &BASE64_STANDARD.encode([43; 65]));
+        assert!(r.is_err(), "Unknown signature header did not return error");
+    }
+}
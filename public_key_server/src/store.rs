@@ -0,0 +1,752 @@
+use crate::submission_log::{LogEntry, LogEvent, LogHead};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{abort, TransactionError, TransactionResult};
+use sled::Transactional;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Number of locks shared between all addresses. Two addresses sharing a lock
+// only means one waits for the other, so this just needs to be more than the
+// number of requests handled at once.
+const ADDRESS_LOCKS: usize = 64;
+
+// Records that don't decode or don't validate are moved here at startup
+const QUARANTINE_TREE: &str = "quarantine";
+
+// Records deleted by an admin are moved here rather than lost
+const DELETED_TREE: &str = "deleted";
+
+// Every submission, delete and quarantine, keyed by seq, so the keys as they
+// were at a round's close can be exported after later submissions have
+// changed them, and followers can copy and check every change
+const LOG_TREE: &str = "log";
+const LOG_HEAD_TREE: &str = "log_head";
+const LOG_HEAD_KEY: &[u8] = b"head";
+
+// How the public key was proven for the address
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmissionMethod {
+    // address and public key given directly
+    PublicKey,
+    // recovered from a signmessage signature
+    SignMessage,
+    // taken from the witness of a BIP322 signature
+    Bip322,
+    // loaded from a keys directory
+    Import,
+    // found on the blockchain by distribute scan-keys and imported by an admin
+    Scan,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KeyRecord {
+    pub pkhex: String,
+    // unix time in seconds
+    pub first_seen: u64,
+    pub last_seen: u64,
+    // the method of the latest submission
+    pub method: SubmissionMethod,
+    pub submissions: u64,
+    // the latest submission was for an address without a MAID balance
+    #[serde(default)]
+    pub flagged: bool,
+    // the round the latest submission was made in, if rounds are used
+    #[serde(default)]
+    pub round: Option<u32>,
+}
+
+// Public keys by address.
+// Submissions for the same address are counted rather than overwriting the
+// record, and the latest public key is kept.
+#[derive(Clone)]
+pub struct KeyStore {
+    db: sled::Db,
+    log: sled::Tree,
+    log_head: sled::Tree,
+    deleted: sled::Tree,
+    quarantine: sled::Tree,
+    // held while an address is being read and written, in the store or as a
+    // file, so submissions and exports for an address happen one at a time
+    locks: Arc<Vec<Mutex<()>>>,
+    // changes that couldn't be written to the database
+    write_failures: Arc<AtomicU64>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+impl KeyStore {
+    pub fn open(path: &str) -> Result<KeyStore, String> {
+        match sled::open(path) {
+            Ok(db) => KeyStore::new(db),
+            Err(e) => Err(format!("Error opening database {}: {}", path, e)),
+        }
+    }
+
+    // An empty store that's removed when dropped, for tests
+    pub fn temporary() -> KeyStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        KeyStore::new(db).unwrap()
+    }
+
+    fn new(db: sled::Db) -> Result<KeyStore, String> {
+        let mut trees = Vec::<sled::Tree>::new();
+        for name in [LOG_TREE, LOG_HEAD_TREE, DELETED_TREE, QUARANTINE_TREE] {
+            match db.open_tree(name) {
+                Ok(t) => trees.push(t),
+                Err(e) => return Err(format!("Error opening {}: {}", name, e)),
+            }
+        }
+        let locks = (0..ADDRESS_LOCKS).map(|_| Mutex::new(())).collect();
+        Ok(KeyStore {
+            db,
+            quarantine: trees.pop().unwrap(),
+            deleted: trees.pop().unwrap(),
+            log_head: trees.pop().unwrap(),
+            log: trees.pop().unwrap(),
+            locks: Arc::new(locks),
+            write_failures: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    fn lock(&self, address: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        address.hash(&mut hasher);
+        let i = hasher.finish() as usize % self.locks.len();
+        // a panic while holding the lock can't leave a record half written
+        // since writes are transactions, so a poisoned lock is still usable
+        match self.locks[i].lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn record(&self, address: &str, pkhex: &str, method: SubmissionMethod, flagged: bool, round: Option<u32>) -> Result<KeyRecord, &'static str> {
+        self.record_at(address, pkhex, method, flagged, round, now())
+    }
+
+    // Saves a submission made at time, which should be the time used to
+    // decide its round so it's exported with that round.
+    pub fn record_at(&self, address: &str, pkhex: &str, method: SubmissionMethod, flagged: bool, round: Option<u32>, time: u64) -> Result<KeyRecord, &'static str> {
+        let change = LogEntry {
+            seq: 0,
+            prev_hash: String::new(),
+            event: LogEvent::Submit,
+            address: address.to_string(),
+            pkhex: pkhex.to_string(),
+            time,
+            method,
+            flagged,
+            round,
+            hash: String::new(),
+        };
+        match self.append(&change, false, false) {
+            Ok(Some(record)) => Ok(record),
+            _ => Err("Error saving record"),
+        }
+    }
+
+    // Adds a change to the end of the log and makes it to the records.
+    // Both are in one transaction so the records and log never disagree, and
+    // are flushed so the change is on disk before the submitter is told it's
+    // saved.
+    // For a new change the seq and hashes are set here. A copy of an entry
+    // from another log must follow the end of this log exactly.
+    // An import is only saved if the address has no record, checked in the
+    // same transaction so a submission made at the same time isn't replaced.
+    // Returns the new record for a submission, or None for an import that
+    // wasn't saved.
+    fn append(&self, change: &LogEntry, copy: bool, import: bool) -> Result<Option<KeyRecord>, String> {
+        let _lock = self.lock(&change.address);
+        let trees = (&*self.db, &self.log, &self.log_head, &self.deleted, &self.quarantine);
+        let result: TransactionResult<Option<KeyRecord>, String> = trees.transaction(|(tx, log, log_head, deleted, quarantine)| {
+            let head = match log_head.get(LOG_HEAD_KEY)? {
+                Some(v) => match serde_json::from_slice::<LogHead>(&v) {
+                    Ok(h) => h,
+                    Err(_) => return abort("Log head can't be read".to_string()),
+                },
+                None => LogHead::default(),
+            };
+            let entry = if copy {
+                if let Err(e) = change.verify_after(&head) {
+                    return abort(e);
+                }
+                change.clone()
+            } else {
+                LogEntry {
+                    seq: head.entries,
+                    prev_hash: head.hash.clone(),
+                    ..change.clone()
+                }.sealed()
+            };
+            let address = entry.address.as_str();
+            let existing = tx.get(address)?;
+            if import && existing.is_some() {
+                return Ok(None);
+            }
+            let record = match entry.event {
+                LogEvent::Submit => {
+                    let existing = existing.and_then(|v| serde_json::from_slice::<KeyRecord>(&v).ok());
+                    let record = match existing {
+                        Some(r) => KeyRecord {
+                            pkhex: entry.pkhex.clone(),
+                            first_seen: r.first_seen,
+                            last_seen: entry.time,
+                            method: entry.method,
+                            submissions: r.submissions + 1,
+                            flagged: entry.flagged,
+                            round: entry.round,
+                        },
+                        None => KeyRecord {
+                            pkhex: entry.pkhex.clone(),
+                            first_seen: entry.time,
+                            last_seen: entry.time,
+                            method: entry.method,
+                            submissions: 1,
+                            flagged: entry.flagged,
+                            round: entry.round,
+                        },
+                    };
+                    tx.insert(address, serde_json::to_vec(&record).unwrap())?;
+                    Some(record)
+                }
+                LogEvent::Delete => {
+                    let value = match existing {
+                        Some(v) => v,
+                        None => return abort(format!("No record to delete for {}", address)),
+                    };
+                    deleted.insert(address, value)?;
+                    tx.remove(address)?;
+                    None
+                }
+                // a copy of the log may not have the corrupt record, which is
+                // still logged so the records end up the same
+                LogEvent::Quarantine => {
+                    if let Some(value) = existing {
+                        quarantine.insert(address, value)?;
+                        tx.remove(address)?;
+                    }
+                    None
+                }
+            };
+            log.insert(&entry.seq.to_be_bytes(), serde_json::to_vec(&entry).unwrap())?;
+            log_head.insert(LOG_HEAD_KEY, serde_json::to_vec(&entry.head()).unwrap())?;
+            Ok(record)
+        });
+        let record = match result {
+            Ok(r) => r,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => {
+                self.write_failures.fetch_add(1, Ordering::Relaxed);
+                return Err(format!("Error saving record: {}", e));
+            }
+        };
+        if let Err(e) = self.db.flush() {
+            self.write_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(format!("Error writing record: {}", e));
+        }
+        Ok(record)
+    }
+
+    pub fn get(&self, address: &str) -> Option<KeyRecord> {
+        match self.db.get(address) {
+            Ok(Some(v)) => serde_json::from_slice(&v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    // The number of changes that failed to be written since the server started
+    pub fn write_failures(&self) -> u64 {
+        self.write_failures.load(Ordering::Relaxed)
+    }
+
+    // Every address and record, in address order
+    pub fn records(&self) -> Vec<(String, KeyRecord)> {
+        let mut records = Vec::<(String, KeyRecord)>::new();
+        for item in self.db.iter().flatten() {
+            let address = String::from_utf8_lossy(&item.0).to_string();
+            if let Ok(record) = serde_json::from_slice::<KeyRecord>(&item.1) {
+                records.push((address, record));
+            }
+        }
+        records
+    }
+
+    // The public key each address had at a time, from the latest submission
+    // up to and including that time.
+    // Only the log up to that time is used, so deletes and quarantines after
+    // it don't change the keys for a round that had already closed.
+    pub fn keys_at(&self, time: u64) -> Vec<(String, String)> {
+        let mut keys = HashMap::<String, String>::new();
+        for entry in self.log.iter().values().flatten() {
+            let entry = match serde_json::from_slice::<LogEntry>(&entry) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            if entry.time > time {
+                continue;
+            }
+            match entry.event {
+                LogEvent::Submit => keys.insert(entry.address, entry.pkhex),
+                LogEvent::Delete | LogEvent::Quarantine => keys.remove(&entry.address),
+            };
+        }
+        let mut keys: Vec<(String, String)> = keys.into_iter().collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn log_head(&self) -> Result<LogHead, String> {
+        match self.log_head.get(LOG_HEAD_KEY) {
+            Ok(Some(v)) => match serde_json::from_slice(&v) {
+                Ok(h) => Ok(h),
+                Err(e) => Err(format!("Error reading log head: {}", e)),
+            },
+            Ok(None) => Ok(LogHead::default()),
+            Err(e) => Err(format!("Error reading log head: {}", e)),
+        }
+    }
+
+    // Up to limit log entries starting at seq from
+    pub fn log_entries(&self, from: u64, limit: usize) -> Result<Vec<LogEntry>, String> {
+        let mut entries = Vec::<LogEntry>::new();
+        for item in self.log.range(from.to_be_bytes()..).values().take(limit) {
+            let value = match item {
+                Ok(v) => v,
+                Err(e) => return Err(format!("Error reading log: {}", e)),
+            };
+            match serde_json::from_slice(&value) {
+                Ok(e) => entries.push(e),
+                Err(e) => return Err(format!("Error reading log entry: {}", e)),
+            }
+        }
+        Ok(entries)
+    }
+
+    // Makes a change read from another server's log, see follower.
+    // The entry must follow the end of this log.
+    pub fn apply_log_entry(&self, entry: &LogEntry) -> Result<(), String> {
+        self.append(entry, true, false).map(|_| ())
+    }
+
+    // Checks every log entry follows the one before and hasn't been changed,
+    // that the log ends at the saved head, that each record has the key the
+    // log says it should and that every address with a key in the log has a
+    // record.
+    // Returns the head.
+    pub fn verify_log(&self) -> Result<LogHead, String> {
+        let mut head = LogHead::default();
+        let mut keys = HashMap::<String, String>::new();
+        for item in self.log.iter().values() {
+            let entry: LogEntry = match item.map(|v| serde_json::from_slice(&v)) {
+                Ok(Ok(e)) => e,
+                _ => return Err(format!("Log entry {} can't be read", head.entries)),
+            };
+            entry.verify_after(&head)?;
+            head = entry.head();
+            match entry.event {
+                LogEvent::Submit => keys.insert(entry.address, entry.pkhex),
+                LogEvent::Delete | LogEvent::Quarantine => keys.remove(&entry.address),
+            };
+        }
+        if head != self.log_head()? {
+            return Err(format!("Log ends at entry {} but the head is {:?}", head.entries, self.log_head()?));
+        }
+        let records = self.records();
+        for (address, record) in &records {
+            if keys.get(address) != Some(&record.pkhex) {
+                return Err(format!("Record for {} doesn't match the log", address));
+            }
+        }
+        // every record matches the log, so a key the log has with no record
+        // means the record was removed without a log entry
+        let addresses: HashSet<&String> = records.iter().map(|(address, _)| address).collect();
+        if let Some(address) = keys.keys().find(|a| !addresses.contains(a)) {
+            return Err(format!("Record for {} is in the log but not in the store", address));
+        }
+        Ok(head)
+    }
+
+    // Writes one file per address containing the public key hex, which is
+    // the keys directory layout read by distribute.
+    pub fn export(&self, dir: &Path) -> Result<usize, String> {
+        let keys = self.records().into_iter().map(|(address, record)| (address, record.pkhex)).collect();
+        self.write_key_files(dir, keys)
+    }
+
+    // Exports the keys as they were when submissions for a round closed, so
+    // the keys used for a round's distribution can be exported again later.
+    pub fn export_at(&self, dir: &Path, time: u64) -> Result<usize, String> {
+        self.write_key_files(dir, self.keys_at(time))
+    }
+
+    fn write_key_files(&self, dir: &Path, keys: Vec<(String, String)>) -> Result<usize, String> {
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(format!("Error creating {}: {}", dir.display(), e));
+        }
+        let mut exported = 0;
+        for (address, pkhex) in keys {
+            // addresses are validated when submitted, but check again since
+            // they become filenames
+            if distribution_core::validate_bitcoin_pair(&address, &pkhex).is_err() {
+                println!("Not exporting invalid record for {}", address);
+                continue;
+            }
+            let filename = dir.join(&address);
+            let _lock = self.lock(&address);
+            if let Err(e) = distribution_core::write_atomic(&filename, pkhex.as_bytes()) {
+                return Err(format!("Error writing {}: {}", filename.display(), e));
+            }
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    // Moves records that can't be decoded or whose key isn't for the address
+    // out of the store, so they're never served or exported, and logs each
+    // move.
+    // Returns the addresses that were moved.
+    pub fn quarantine_corrupt(&self) -> Result<Vec<String>, String> {
+        let mut corrupt = Vec::<(String, Option<KeyRecord>)>::new();
+        for item in self.db.iter() {
+            let (key, value) = match item {
+                Ok(kv) => kv,
+                Err(e) => return Err(format!("Error reading database: {}", e)),
+            };
+            let address = String::from_utf8_lossy(&key).to_string();
+            let record = serde_json::from_slice::<KeyRecord>(&value).ok();
+            match &record {
+                Some(r) if distribution_core::validate_bitcoin_pair(&address, &r.pkhex).is_ok() => {}
+                _ => corrupt.push((address, record)),
+            }
+        }
+        let mut moved = Vec::<String>::new();
+        for (address, record) in corrupt {
+            // a record that can't be decoded is logged without a key
+            let change = LogEntry {
+                seq: 0,
+                prev_hash: String::new(),
+                event: LogEvent::Quarantine,
+                address: address.clone(),
+                pkhex: record.as_ref().map(|r| r.pkhex.clone()).unwrap_or_default(),
+                time: now(),
+                method: record.as_ref().map(|r| r.method).unwrap_or(SubmissionMethod::Import),
+                flagged: record.as_ref().map(|r| r.flagged).unwrap_or(false),
+                round: record.and_then(|r| r.round),
+                hash: String::new(),
+            };
+            if let Err(e) = self.append(&change, false, false) {
+                return Err(format!("Error quarantining {}: {}", address, e));
+            }
+            moved.push(address);
+        }
+        Ok(moved)
+    }
+
+    // Saves a key from another source unless the address already has one.
+    // Returns whether it was saved.
+    pub fn import_key(&self, address: &str, pkhex: &str, method: SubmissionMethod, time: u64) -> Result<bool, &'static str> {
+        let change = LogEntry {
+            seq: 0,
+            prev_hash: String::new(),
+            event: LogEvent::Submit,
+            address: address.to_string(),
+            pkhex: pkhex.to_string(),
+            time,
+            method,
+            flagged: false,
+            round: None,
+            hash: String::new(),
+        };
+        match self.append(&change, false, true) {
+            Ok(Some(_)) => Ok(true),
+            Ok(None) => Ok(false),
+            Err(_) => Err("Error saving record"),
+        }
+    }
+
+    // Moves the record for an address to the deleted tree, so it's no longer
+    // served or exported, and logs the delete. Returns false if there's no
+    // record.
+    pub fn delete(&self, address: &str) -> Result<bool, String> {
+        let record = match self.get(address) {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        let change = LogEntry {
+            seq: 0,
+            prev_hash: String::new(),
+            event: LogEvent::Delete,
+            address: address.to_string(),
+            pkhex: record.pkhex,
+            time: now(),
+            method: record.method,
+            flagged: record.flagged,
+            round: record.round,
+            hash: String::new(),
+        };
+        match self.append(&change, false, false) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(format!("Error deleting {}: {}", address, e)),
+        }
+    }
+
+    // Loads a keys directory saved by earlier versions of the server.
+    // Addresses already in the store are left as they are.
+    // Returns the number imported and the reason for each file skipped.
+    pub fn import(&self, dir: &Path) -> Result<(usize, Vec<String>), String> {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Error reading {}: {}", dir.display(), e)),
+        };
+        let mut imported = 0;
+        let mut skipped = Vec::<String>::new();
+        for entry in entries.flatten() {
+            let address = distribution_core::canonical_address(&entry.file_name().to_string_lossy());
+            let pkhex = match fs::read_to_string(entry.path()) {
+                Ok(pk) => pk.trim().to_lowercase(),
+                Err(_) => {
+                    skipped.push(format!("{}: Unreadable file", address));
+                    continue;
+                }
+            };
+            if let Err(e) = distribution_core::validate_bitcoin_pair(&address, &pkhex) {
+                skipped.push(format!("{}: {}", address, e));
+                continue;
+            }
+            // the file time is the best guess of when it was submitted
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            let time = match modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+                Some(d) => d.as_secs(),
+                None => now(),
+            };
+            if self.import_key(&address, &pkhex, SubmissionMethod::Import, time)? {
+                imported += 1;
+            }
+        }
+        Ok((imported, skipped))
+    }
+}
+
+pub fn tests() {
+    let address = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    let pkhex = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    // first submission
+    {
+        let store = KeyStore::temporary();
+        let record = store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, None, 1000).unwrap();
+        assert!(record.first_seen == 1000 && record.last_seen == 1000, "New record has wrong times: {:?}", record);
+        assert!(record.submissions == 1, "New record has {} submissions", record.submissions);
+        assert!(store.get(address) == Some(record), "Saved record not found");
+        assert!(store.get("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm").is_none(), "Record found for unknown address");
+    }
+    // repeat submissions are counted
+    {
+        let store = KeyStore::temporary();
+        store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, None, 1000).unwrap();
+        let record = store.record_at(address, pkhex, SubmissionMethod::Bip322, true, Some(1), 2000).unwrap();
+        assert!(record.first_seen == 1000, "Repeat submission changed first seen: {:?}", record);
+        assert!(record.last_seen == 2000, "Repeat submission didn't change last seen: {:?}", record);
+        assert!(record.submissions == 2, "Repeat submission was not counted: {:?}", record);
+        assert!(record.method == SubmissionMethod::Bip322, "Repeat submission didn't change method: {:?}", record);
+        assert!(record.flagged, "Repeat submission didn't change flag: {:?}", record);
+        assert!(record.round == Some(1), "Repeat submission didn't change round: {:?}", record);
+        assert!(store.len() == 1, "Repeat submission made another record");
+    }
+    // keys at a round's close ignore later submissions
+    {
+        let store = KeyStore::temporary();
+        let other_pkhex = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        let other_address = "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm";
+        store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, Some(1), 1000).unwrap();
+        store.record_at(other_address, other_pkhex, SubmissionMethod::PublicKey, false, Some(2), 3000).unwrap();
+        store.record_at(address, "02", SubmissionMethod::PublicKey, false, Some(2), 3500).unwrap();
+        let keys = store.keys_at(2000);
+        assert!(keys == vec![(address.to_string(), pkhex.to_string())], "Keys at first round close are {:?}", keys);
+        let keys = store.keys_at(4000);
+        assert!(keys.len() == 2 && keys.contains(&(address.to_string(), "02".to_string())), "Keys at second round close are {:?}", keys);
+        assert!(store.keys_at(999).is_empty(), "Keys found before any submission");
+        // a delete after a round closes leaves that round's keys as they were
+        let before = store.keys_at(4000);
+        store.delete(other_address).unwrap();
+        let keys = store.keys_at(4000);
+        assert!(keys == before, "Keys at second round close after delete are {:?}", keys);
+        let keys = store.keys_at(now());
+        assert!(keys == vec![(address.to_string(), "02".to_string())], "Keys after delete are {:?}", keys);
+    }
+    // every change is logged and the log can be verified
+    {
+        let store = KeyStore::temporary();
+        store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, None, 1000).unwrap();
+        store.record_at(address, pkhex, SubmissionMethod::Bip322, false, Some(1), 2000).unwrap();
+        store.delete(address).unwrap();
+        let entries = store.log_entries(0, 10).unwrap();
+        let events: Vec<LogEvent> = entries.iter().map(|e| e.event).collect();
+        assert!(events == vec![LogEvent::Submit, LogEvent::Submit, LogEvent::Delete], "Logged events are {:?}", events);
+        assert!(store.log_entries(1, 1).unwrap() == entries[1..2], "Log from an entry is wrong");
+        let head = store.verify_log();
+        assert!(head == Ok(entries[2].head()), "Valid log gave {:?}", head);
+        // a changed entry
+        let mut changed = entries[0].clone();
+        changed.time = 500;
+        store.log.insert(0u64.to_be_bytes(), serde_json::to_vec(&changed).unwrap()).unwrap();
+        assert!(store.verify_log().is_err(), "Changed log entry verified");
+        store.log.insert(0u64.to_be_bytes(), serde_json::to_vec(&entries[0]).unwrap()).unwrap();
+        // a record changed without a log entry
+        store.db.insert(address, serde_json::to_vec(&KeyRecord {
+            pkhex: pkhex.to_string(),
+            first_seen: 1000,
+            last_seen: 1000,
+            method: SubmissionMethod::PublicKey,
+            submissions: 1,
+            flagged: false,
+            round: None,
+        }).unwrap()).unwrap();
+        assert!(store.verify_log().is_err(), "Record not in the log verified");
+        store.db.remove(address).unwrap();
+        // a removed entry
+        store.log.remove(2u64.to_be_bytes()).unwrap();
+        assert!(store.verify_log().is_err(), "Log missing its last entry verified");
+    }
+    // a record removed without a log entry
+    {
+        let store = KeyStore::temporary();
+        store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, None, 1000).unwrap();
+        assert!(store.verify_log().is_ok(), "Valid log did not verify");
+        store.db.remove(address).unwrap();
+        let r = store.verify_log();
+        assert!(r.as_ref().is_err_and(|e| e.contains(address)), "Record missing from the store gave {:?}", r);
+    }
+    // a copy of the log makes the same records
+    {
+        let primary = KeyStore::temporary();
+        let follower = KeyStore::temporary();
+        primary.record_at(address, pkhex, SubmissionMethod::PublicKey, true, Some(1), 1000).unwrap();
+        primary.record_at("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8", SubmissionMethod::SignMessage, false, None, 2000).unwrap();
+        primary.delete("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm").unwrap();
+        let entries = primary.log_entries(0, 10).unwrap();
+        for entry in &entries {
+            follower.apply_log_entry(entry).unwrap();
+        }
+        assert!(follower.records() == primary.records(), "Follower records are {:?}", follower.records());
+        assert!(follower.log_head() == primary.log_head(), "Follower head differs");
+        assert!(follower.verify_log().is_ok(), "Follower log did not verify");
+        // entries already applied, or changed, are rejected
+        assert!(follower.apply_log_entry(&entries[0]).is_err(), "Repeated entry was applied");
+        let mut changed = entries[1].clone();
+        changed.seq = 3;
+        assert!(follower.apply_log_entry(&changed).is_err(), "Changed entry was applied");
+        assert!(follower.log_head().map(|h| h.entries) == Ok(3), "Rejected entries changed the log");
+    }
+    // export and import use the keys directory layout
+    {
+        let dir = std::env::temp_dir().join(format!("public_key_server_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = KeyStore::temporary();
+        store.record(address, pkhex, SubmissionMethod::PublicKey, false, None).unwrap();
+        let exported = store.export(&dir);
+        assert!(exported == Ok(1), "Export gave {:?}", exported);
+        let content = fs::read_to_string(dir.join(address)).unwrap();
+        assert!(content == pkhex, "Exported key is {}", content);
+        // invalid files are not imported
+        fs::write(dir.join("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm"), pkhex).unwrap();
+        let imported_store = KeyStore::temporary();
+        let imported = imported_store.import(&dir);
+        assert!(imported.as_ref().map(|i| i.0) == Ok(1), "Import gave {:?}", imported);
+        assert!(imported.map(|i| i.1.len()) == Ok(1), "Invalid file was not skipped");
+        let record = imported_store.get(address).unwrap();
+        assert!(record.pkhex == pkhex && record.method == SubmissionMethod::Import, "Imported record is {:?}", record);
+        // importing again leaves existing records
+        let imported = imported_store.import(&dir);
+        assert!(imported.map(|i| i.0) == Ok(0), "Second import imported existing records");
+        let _ = fs::remove_dir_all(&dir);
+    }
+    // corrupt records are quarantined
+    {
+        let store = KeyStore::temporary();
+        store.record(address, pkhex, SubmissionMethod::PublicKey, false, None).unwrap();
+        // truncated record
+        store.db.insert("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm", &b"{\"pkhex\":\"04"[..]).unwrap();
+        // truncated key
+        let mut record = store.get(address).unwrap();
+        record.pkhex = pkhex[..20].to_string();
+        store.db.insert("3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN", serde_json::to_vec(&record).unwrap()).unwrap();
+        let moved = store.quarantine_corrupt().unwrap();
+        assert!(moved.len() == 2, "Quarantined {:?}", moved);
+        assert!(store.len() == 1 && store.get(address).is_some(), "Valid record was quarantined");
+        assert!(store.quarantine.len() == 2, "Quarantine has {} records", store.quarantine.len());
+        // each move is logged, so the records still match the log
+        let entries = store.log_entries(0, 10).unwrap();
+        let events: Vec<LogEvent> = entries.iter().map(|e| e.event).collect();
+        assert!(events == vec![LogEvent::Submit, LogEvent::Quarantine, LogEvent::Quarantine], "Logged events are {:?}", events);
+        assert!(store.verify_log().is_ok(), "Log after quarantine did not verify");
+        // a copy of the log without the corrupt records makes the same records
+        let follower = KeyStore::temporary();
+        for entry in &entries {
+            follower.apply_log_entry(entry).unwrap();
+        }
+        assert!(follower.records() == store.records(), "Follower records are {:?}", follower.records());
+        let moved = store.quarantine_corrupt().unwrap();
+        assert!(moved.is_empty(), "Quarantined again {:?}", moved);
+    }
+    // deleted records are moved out of the store
+    {
+        let store = KeyStore::temporary();
+        store.record(address, pkhex, SubmissionMethod::PublicKey, false, None).unwrap();
+        assert!(store.delete(address) == Ok(true), "Record was not deleted");
+        assert!(store.get(address).is_none() && store.len() == 0, "Deleted record is still in the store");
+        assert!(store.delete(address) == Ok(false), "Deleting missing record did not return false");
+        let deleted = store.db.open_tree(DELETED_TREE).unwrap().len();
+        assert!(deleted == 1, "Deleted tree has {} records", deleted);
+    }
+    // imported keys don't replace existing records
+    {
+        let store = KeyStore::temporary();
+        assert!(store.import_key(address, pkhex, SubmissionMethod::Scan, 1000) == Ok(true), "Scanned key was not imported");
+        store.record_at(address, pkhex, SubmissionMethod::PublicKey, false, None, 2000).unwrap();
+        assert!(store.import_key(address, pkhex, SubmissionMethod::Scan, 3000) == Ok(false), "Scanned key replaced existing record");
+        let record = store.get(address).unwrap();
+        assert!(record.method == SubmissionMethod::PublicKey && record.last_seen == 2000, "Record after import is {:?}", record);
+        assert!(store.log_head().unwrap().entries == 2, "Import that wasn't saved was logged");
+    }
+    // an import at the same time as a submission never replaces it
+    for _ in 0..20 {
+        let store = KeyStore::temporary();
+        let submitter = store.clone();
+        let submission = std::thread::spawn(move || submitter.record(address, pkhex, SubmissionMethod::PublicKey, false, None).unwrap());
+        let imported = store.import_key(address, pkhex, SubmissionMethod::Scan, now()).unwrap();
+        submission.join().unwrap();
+        let record = store.get(address).unwrap();
+        let expected = if imported { 2 } else { 1 };
+        assert!(record.method == SubmissionMethod::PublicKey && record.submissions == expected, "Record after import {} is {:?}", imported, record);
+    }
+    // concurrent submissions for an address are all counted
+    {
+        let store = KeyStore::temporary();
+        let threads: Vec<_> = (0..8).map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    store.record(address, pkhex, SubmissionMethod::PublicKey, false, None).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let record = store.get(address).unwrap();
+        assert!(record.submissions == 80, "Concurrent submissions counted {} times", record.submissions);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::store::SubmissionMethod;
+use bitcoin::hashes::{sha256, Hash};
+use serde::{Deserialize, Serialize};
+
+// The prev_hash of the first entry
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogEvent {
+    // a key was saved for the address
+    Submit,
+    // the record for the address was deleted by an admin
+    Delete,
+    // the record for the address couldn't be read or its key wasn't for the
+    // address, so it was moved to the quarantine when the server started
+    Quarantine,
+}
+
+// One change to the store.
+// Each entry includes the hash of the one before it, so changing or removing
+// any entry changes the hash of every entry after it, and a copy of the log
+// can be checked against the latest hash.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogEntry {
+    // starts at 0 and increases by 1 with each entry
+    pub seq: u64,
+    pub prev_hash: String,
+    pub event: LogEvent,
+    pub address: String,
+    // for a delete or quarantine, the key of the record, if it could be read
+    pub pkhex: String,
+    // unix time in seconds
+    pub time: u64,
+    pub method: SubmissionMethod,
+    pub flagged: bool,
+    pub round: Option<u32>,
+    pub hash: String,
+}
+
+// The end of the log, which the next entry follows
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogHead {
+    // the number of entries, which is the seq of the next entry
+    pub entries: u64,
+    // the hash of the last entry
+    pub hash: String,
+}
+
+impl Default for LogHead {
+    fn default() -> LogHead {
+        LogHead {
+            entries: 0,
+            hash: GENESIS_HASH.to_string(),
+        }
+    }
+}
+
+impl LogEntry {
+    // Sets the hash once the other fields are filled in
+    pub fn sealed(mut self) -> LogEntry {
+        self.hash = self.compute_hash();
+        self
+    }
+
+    // sha256 of every field except the hash, one per line.
+    // Enums are hashed by their json names so the hash doesn't depend on how
+    // they're declared.
+    pub fn compute_hash(&self) -> String {
+        let round = match self.round {
+            Some(r) => r.to_string(),
+            None => "null".to_string(),
+        };
+        let text = format!(
+            "public_key_server log v1\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            self.seq,
+            self.prev_hash,
+            serde_json::to_value(self.event).unwrap().as_str().unwrap_or(""),
+            self.address,
+            self.pkhex,
+            self.time,
+            serde_json::to_value(self.method).unwrap().as_str().unwrap_or(""),
+            self.flagged,
+            round,
+        );
+        hex::encode(sha256::Hash::hash(text.as_bytes()).to_byte_array())
+    }
+
+    // Checks this entry is the one that follows head and hasn't been changed
+    pub fn verify_after(&self, head: &LogHead) -> Result<(), String> {
+        if self.seq != head.entries {
+            return Err(format!("Log entry {} found where {} was expected", self.seq, head.entries));
+        }
+        if self.prev_hash != head.hash {
+            return Err(format!("Log entry {} does not follow the previous entry", self.seq));
+        }
+        if self.hash != self.compute_hash() {
+            return Err(format!("Log entry {} has been changed", self.seq));
+        }
+        if self.event == LogEvent::Submit {
+            if let Err(e) = distribution_core::validate_bitcoin_pair(&self.address, &self.pkhex) {
+                return Err(format!("Log entry {} has an invalid key: {}", self.seq, e));
+            }
+        }
+        Ok(())
+    }
+
+    // The head after this entry
+    pub fn head(&self) -> LogHead {
+        LogHead {
+            entries: self.seq + 1,
+            hash: self.hash.clone(),
+        }
+    }
+}
+
+// Checks entries follow on from head, returning the head after the last one
+pub fn verify_chain(head: &LogHead, entries: &[LogEntry]) -> Result<LogHead, String> {
+    let mut head = head.clone();
+    for entry in entries {
+        entry.verify_after(&head)?;
+        head = entry.head();
+    }
+    Ok(head)
+}
+
+pub fn tests() {
+    let address = "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH";
+    let pkhex = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let entry = |head: &LogHead, event: LogEvent, pkhex: &str, time: u64| LogEntry {
+        seq: head.entries,
+        prev_hash: head.hash.clone(),
+        event,
+        address: address.to_string(),
+        pkhex: pkhex.to_string(),
+        time,
+        method: SubmissionMethod::PublicKey,
+        flagged: false,
+        round: Some(1),
+        hash: String::new(),
+    }.sealed();
+    let first = entry(&LogHead::default(), LogEvent::Submit, pkhex, 1000);
+    let second = entry(&first.head(), LogEvent::Delete, pkhex, 2000);
+    let entries = vec![first.clone(), second.clone()];
+    // a chain verifies
+    {
+        assert!(first.seq == 0 && first.prev_hash == GENESIS_HASH, "First entry is {:?}", first);
+        assert!(second.prev_hash == first.hash, "Second entry doesn't follow the first");
+        let head = verify_chain(&LogHead::default(), &entries);
+        assert!(head == Ok(second.head()), "Valid chain gave {:?}", head);
+        assert!(first.hash.len() == 64 && first.hash != second.hash, "Entry hashes are {} and {}", first.hash, second.hash);
+    }
+    // changed entries don't verify
+    {
+        let mut changed = entries.clone();
+        changed[0].pkhex = "02".to_string();
+        assert!(verify_chain(&LogHead::default(), &changed).is_err(), "Changed key verified");
+        let mut changed = entries.clone();
+        changed[1].round = None;
+        assert!(verify_chain(&LogHead::default(), &changed).is_err(), "Changed round verified");
+        // rehashing a changed entry breaks the link to the next entry
+        let mut changed = entries.clone();
+        changed[0].time = 999;
+        changed[0].hash = changed[0].compute_hash();
+        assert!(verify_chain(&LogHead::default(), &changed).is_err(), "Rehashed entry verified");
+    }
+    // missing and reordered entries don't verify
+    {
+        assert!(verify_chain(&LogHead::default(), &entries[1..]).is_err(), "Chain missing its first entry verified");
+        let reordered = vec![second.clone(), first.clone()];
+        assert!(verify_chain(&LogHead::default(), &reordered).is_err(), "Reordered chain verified");
+        assert!(verify_chain(&first.head(), &entries[1..]) == Ok(second.head()), "Chain from a head did not verify");
+    }
+    // submitted keys must be for the address
+    {
+        let bad = entry(&LogHead::default(), LogEvent::Submit, "02", 1000);
+        assert!(bad.verify_after(&LogHead::default()).is_err(), "Entry with invalid key verified");
+    }
+}